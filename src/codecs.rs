@@ -0,0 +1,321 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use occule::{Codec, Error};
+use sha2::{Digest, Sha256};
+
+use crate::resources::RegisteredCodec;
+
+/// Magic bytes ending files which carry a trailer payload
+const TRAILER_MAGIC: &[u8; 8] = b"DIRWORLD";
+
+/// Length of the truncated SHA-256 checksum of a trailer payload
+const CHECKSUM_LEN: usize = 8;
+
+/// Length of the footer following a trailer payload: its length, checksum and magic bytes
+const FOOTER_LEN: usize = 8 + CHECKSUM_LEN + TRAILER_MAGIC.len();
+
+/// Payload size beyond which probed codecs are assumed to have unlimited capacity
+const MAX_PROBED_CAPACITY: usize = 1 << 26;
+
+/// How many payload bytes a carrier can hold. Ordered by size, so the largest of several
+/// capacities is their maximum.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PayloadCapacity {
+    /// The carrier can hold payloads of up to this many bytes
+    Limited(usize),
+    /// The carrier can hold payloads of any size
+    Unlimited,
+}
+
+impl PayloadCapacity {
+    /// Whether a payload of the given size in bytes fits
+    pub fn fits(&self, size: usize) -> bool {
+        match self {
+            PayloadCapacity::Limited(capacity) => size <= *capacity,
+            PayloadCapacity::Unlimited => true,
+        }
+    }
+}
+
+/// [`Codec`] which can report how many payload bytes a carrier can hold, so payloads can be checked
+/// before they are saved
+pub trait CapacityCodec: Codec {
+    /// How many payload bytes the given carrier can hold. The carrier must not already carry a
+    /// payload; see [`carrier_without_payload`].
+    fn capacity(&self, carrier: &[u8]) -> PayloadCapacity;
+}
+
+/// Wrapper for a [`Codec`] which cannot report its capacity itself, finding it instead by encoding
+/// payloads of increasing size until one fails. This takes a few dozen encodes, so codecs with a
+/// known capacity should implement [`CapacityCodec`] and be registered with
+/// [`crate::DirworldApp::register_dirworld_entry_codec_with_capacity`] instead.
+pub struct ProbedCodec<C: Codec>(pub C);
+
+impl<C: Codec> Codec for ProbedCodec<C> {
+    fn encode(&self, carrier: &[u8], payload: &[u8]) -> Result<Vec<u8>, Error> {
+        self.0.encode(carrier, payload)
+    }
+
+    fn decode(&self, encoded: &[u8]) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        self.0.decode(encoded)
+    }
+}
+
+impl<C: Codec> CapacityCodec for ProbedCodec<C> {
+    fn capacity(&self, carrier: &[u8]) -> PayloadCapacity {
+        let fits = |size: usize| self.0.encode(carrier, &vec![0; size]).is_ok();
+        if !fits(0) {
+            return PayloadCapacity::Limited(0);
+        }
+
+        // Double the size until a payload fails to fit, then bisect between the last two sizes
+        let mut low = 0;
+        let mut high = 1;
+        while fits(high) {
+            if high >= MAX_PROBED_CAPACITY {
+                return PayloadCapacity::Unlimited;
+            }
+            low = high;
+            high *= 2;
+        }
+        while high - low > 1 {
+            let middle = low + (high - low) / 2;
+            if fits(middle) {
+                low = middle;
+            } else {
+                high = middle;
+            }
+        }
+        PayloadCapacity::Limited(low)
+    }
+}
+
+/// Strip the payload a file carries, if any of the given codecs can decode one, leaving the
+/// carrier it was embedded in
+pub fn carrier_without_payload(
+    codecs: &[RegisteredCodec],
+    raw_carrier: Vec<u8>,
+) -> Result<Vec<u8>, Error> {
+    for codec in codecs {
+        match codec.decode(&raw_carrier) {
+            Ok((carrier, _)) => return Ok(carrier),
+            Err(Error::DependencyError(e)) => return Err(Error::DependencyError(e)),
+            Err(_) => {}
+        }
+    }
+    Ok(raw_carrier)
+}
+
+/// Fallback [`Codec`] which appends payloads to the end of a file, followed by a footer holding
+/// their length, a checksum, and magic bytes, and strips them again on decode. It works for any
+/// format whose readers ignore trailing bytes, such as zip and tar archives, PDFs, and many media
+/// containers, but the payload is plainly visible to anyone inspecting the file.
+///
+/// Register it for extensions with [`crate::DirworldApp::register_dirworld_trailer_codec`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TrailerCodec;
+
+impl Codec for TrailerCodec {
+    fn encode(&self, carrier: &[u8], payload: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut encoded = Vec::with_capacity(carrier.len() + payload.len() + FOOTER_LEN);
+        encoded.extend_from_slice(carrier);
+        encoded.extend_from_slice(payload);
+        encoded.extend((payload.len() as u64).to_le_bytes());
+        encoded.extend(checksum(payload));
+        encoded.extend(TRAILER_MAGIC);
+        Ok(encoded)
+    }
+
+    fn decode(&self, encoded: &[u8]) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        let Some(footer_start) = encoded.len().checked_sub(FOOTER_LEN) else {
+            return Err(Error::DataNotEncoded);
+        };
+        let (payload_start, payload_checksum) =
+            parse_footer(&encoded[footer_start..], footer_start as u64)?;
+        let (carrier, payload) = encoded[..footer_start].split_at(payload_start as usize);
+        verify_checksum(payload, payload_checksum)?;
+        Ok((carrier.to_vec(), payload.to_vec()))
+    }
+}
+
+impl TrailerCodec {
+    /// Read the payload appended to a file, seeking past the rest of it, e.g. to read the payload
+    /// of a large locked door without reading its archive
+    pub fn decode_payload(&self, mut file: impl Read + Seek) -> Result<Vec<u8>, Error> {
+        let io_error = |e: std::io::Error| Error::DataInvalid(e.to_string());
+        let Some(footer_start) = file
+            .seek(SeekFrom::End(0))
+            .map_err(io_error)?
+            .checked_sub(FOOTER_LEN as u64)
+        else {
+            return Err(Error::DataNotEncoded);
+        };
+        let mut footer = [0; FOOTER_LEN];
+        file.seek(SeekFrom::Start(footer_start))
+            .and_then(|_| file.read_exact(&mut footer))
+            .map_err(io_error)?;
+        let (payload_start, payload_checksum) = parse_footer(&footer, footer_start)?;
+
+        let mut payload = vec![0; (footer_start - payload_start) as usize];
+        file.seek(SeekFrom::Start(payload_start))
+            .and_then(|_| file.read_exact(&mut payload))
+            .map_err(io_error)?;
+        verify_checksum(&payload, payload_checksum)?;
+        Ok(payload)
+    }
+}
+
+impl CapacityCodec for TrailerCodec {
+    fn capacity(&self, _carrier: &[u8]) -> PayloadCapacity {
+        PayloadCapacity::Unlimited
+    }
+}
+
+/// Parse the footer of a trailer payload which starts at the given offset, returning the offset of
+/// the payload and its checksum
+fn parse_footer(footer: &[u8], footer_start: u64) -> Result<(u64, [u8; CHECKSUM_LEN]), Error> {
+    if !footer.ends_with(TRAILER_MAGIC) {
+        return Err(Error::DataNotEncoded);
+    }
+    let (length, footer) = footer.split_at(8);
+    let length = u64::from_le_bytes(length.try_into().unwrap());
+    let payload_start = footer_start.checked_sub(length).ok_or_else(|| {
+        Error::DataInvalid(format!("Trailer payload length {length} exceeds file size"))
+    })?;
+    Ok((payload_start, footer[..CHECKSUM_LEN].try_into().unwrap()))
+}
+
+fn verify_checksum(payload: &[u8], expected: [u8; CHECKSUM_LEN]) -> Result<(), Error> {
+    if checksum(payload) != expected {
+        return Err(Error::DataInvalid(
+            "Trailer payload checksum does not match".into(),
+        ));
+    }
+    Ok(())
+}
+
+fn checksum(payload: &[u8]) -> [u8; CHECKSUM_LEN] {
+    Sha256::digest(payload)[..CHECKSUM_LEN].try_into().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// Codec which holds payloads of up to a fixed size in a prefix of the carrier
+    struct FixedCodec(usize);
+
+    impl Codec for FixedCodec {
+        fn encode(&self, carrier: &[u8], payload: &[u8]) -> Result<Vec<u8>, Error> {
+            if payload.len() > self.0 {
+                return Err(Error::DataInvalid("Payload does not fit".into()));
+            }
+            Ok([&[payload.len() as u8], payload, carrier].concat())
+        }
+
+        fn decode(&self, encoded: &[u8]) -> Result<(Vec<u8>, Vec<u8>), Error> {
+            let (length, rest) = encoded.split_first().ok_or(Error::DataNotEncoded)?;
+            let (payload, carrier) = rest.split_at(*length as usize);
+            Ok((carrier.to_vec(), payload.to_vec()))
+        }
+    }
+
+    /// Codec which holds payloads of any size
+    struct UnlimitedCodec;
+
+    impl Codec for UnlimitedCodec {
+        fn encode(&self, carrier: &[u8], _payload: &[u8]) -> Result<Vec<u8>, Error> {
+            Ok(carrier.to_vec())
+        }
+
+        fn decode(&self, _encoded: &[u8]) -> Result<(Vec<u8>, Vec<u8>), Error> {
+            Err(Error::DataNotEncoded)
+        }
+    }
+
+    #[test]
+    fn trailer_round_trip() {
+        let carrier = b"PK\x05\x06 archive".to_vec();
+        for payload in [&b""[..], b"payload"] {
+            let encoded = TrailerCodec.encode(&carrier, payload).unwrap();
+            assert_eq!(
+                TrailerCodec.decode(&encoded).unwrap(),
+                (carrier.clone(), payload.to_vec())
+            );
+            assert_eq!(
+                TrailerCodec.decode_payload(Cursor::new(&encoded)).unwrap(),
+                payload
+            );
+        }
+    }
+
+    #[test]
+    fn trailer_not_encoded() {
+        assert!(matches!(
+            TrailerCodec.decode(b"short"),
+            Err(Error::DataNotEncoded)
+        ));
+        assert!(matches!(
+            TrailerCodec.decode(&[0; 64]),
+            Err(Error::DataNotEncoded)
+        ));
+        assert!(matches!(
+            TrailerCodec.decode_payload(Cursor::new(b"short")),
+            Err(Error::DataNotEncoded)
+        ));
+    }
+
+    #[test]
+    fn trailer_corrupted_checksum() {
+        let mut encoded = TrailerCodec.encode(b"carrier", b"payload").unwrap();
+        let payload_start = encoded.len() - FOOTER_LEN - 1;
+        encoded[payload_start] ^= 1;
+        assert!(matches!(
+            TrailerCodec.decode(&encoded),
+            Err(Error::DataInvalid(_))
+        ));
+        assert!(matches!(
+            TrailerCodec.decode_payload(Cursor::new(&encoded)),
+            Err(Error::DataInvalid(_))
+        ));
+    }
+
+    #[test]
+    fn trailer_length_exceeds_file() {
+        let mut encoded = TrailerCodec.encode(b"carrier", b"payload").unwrap();
+        let length_start = encoded.len() - FOOTER_LEN;
+        encoded[length_start..length_start + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(
+            TrailerCodec.decode(&encoded),
+            Err(Error::DataInvalid(_))
+        ));
+        assert!(matches!(
+            TrailerCodec.decode_payload(Cursor::new(&encoded)),
+            Err(Error::DataInvalid(_))
+        ));
+    }
+
+    #[test]
+    fn probed_capacity() {
+        for capacity in [0, 1, 2, 37, 64, 200] {
+            assert_eq!(
+                ProbedCodec(FixedCodec(capacity)).capacity(b"carrier"),
+                PayloadCapacity::Limited(capacity)
+            );
+        }
+        assert_eq!(
+            ProbedCodec(UnlimitedCodec).capacity(b"carrier"),
+            PayloadCapacity::Unlimited
+        );
+    }
+
+    #[test]
+    fn capacity_fits() {
+        assert!(PayloadCapacity::Limited(4).fits(4));
+        assert!(!PayloadCapacity::Limited(4).fits(5));
+        assert!(PayloadCapacity::Unlimited.fits(usize::MAX));
+        assert!(PayloadCapacity::Limited(usize::MAX) < PayloadCapacity::Unlimited);
+    }
+}
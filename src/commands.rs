@@ -1,28 +1,60 @@
-use std::{fs, path::PathBuf};
+use std::{
+    fs::{self, File},
+    io::{BufReader, Cursor, Read},
+    path::{Path, PathBuf},
+};
 
 use bevy::{
     ecs::world::{Command, CommandQueue},
     prelude::*,
     tasks::AsyncComputeTaskPool,
 };
-use crypto::{
-    aes::KeySize,
-    blockmodes::PkcsPadding,
-    buffer::{BufferResult, ReadBuffer, RefReadBuffer, RefWriteBuffer, WriteBuffer},
-};
 use occule::Error;
-use xz2::read::{XzDecoder, XzEncoder};
+use uuid::Uuid;
 
 use crate::{
-    payload::DirworldEntityPayload,
-    resources::{DirworldCodecs, DirworldObservers, DirworldTasks},
-    utils::extract_entity_payload,
+    cache::DirworldCache,
+    codecs::{carrier_without_payload, PayloadCapacity},
+    components::{DirworldEntity, Unsaved, UntrustedPayload},
+    door::{
+        clear_lock, extract_archive, is_locked_door, is_streamable_door, locked_door,
+        locked_door_path, locked_entry_name, new_door_key, new_shared_door_key, read_journal,
+        read_locked_archive, reencrypt_archive, roll_back, roll_forward, sync_tree, temp_path,
+        write_durably, write_locked_archive, DoorError, DoorJournal, JournalEntry,
+        JournalOperation, JournalPhase, JOURNAL_DIR, KEY_RELATIONSHIP,
+    },
+    events::{
+        DirworldDoorKeysMissing, DirworldDoorLocked, DirworldDoorOperationFailed,
+        DirworldDoorRekeyed, DirworldDoorUnlocked, DirworldPayloadSaved, DirworldPayloadTooLarge,
+        DirworldReferenceMissing, DirworldTaskStarted,
+    },
+    payload::{
+        components::{LockedFile, LockedRoom, SourceReference},
+        DirworldEntityPayload, PayloadError, PayloadSignature,
+    },
+    resources::{
+        DirworldCancellationToken, DirworldCodecs, DirworldExtractionLimits,
+        DirworldPayloadMigrations, DirworldRootDir, DirworldTask, DirworldTaskId, DirworldTaskKind,
+        DirworldTaskProgress, DirworldTasks, DirworldWorldKey, RegisteredCodec,
+    },
+    utils::{extract_entity_payload, remove_sidecar, sidecar_path, READABLE_DOOR_FILE},
     Extensions,
 };
 
 struct DirworldLockDoorCommand {
     path: PathBuf,
-    key: Vec<u8>,
+    keys: LockKeys,
+}
+
+/// Passphrases a door is locked with
+enum LockKeys {
+    /// A single passphrase
+    Single(Vec<u8>),
+    /// Labelled passphrases, any `threshold` of which unlock the door
+    Shared {
+        keys: Vec<(String, Vec<u8>)>,
+        threshold: usize,
+    },
 }
 
 impl Command for DirworldLockDoorCommand {
@@ -30,141 +62,674 @@ impl Command for DirworldLockDoorCommand {
         let path = self.path.clone();
         // Get existing payload
         let codecs = world.remove_resource::<DirworldCodecs>().unwrap();
-        let (payload, _) = extract_entity_payload(&path, &codecs);
+        let (payload, _, signature) =
+            extract_entity_payload(&path, &codecs, world.resource(), world.resource());
         world.insert_resource(codecs);
-        let task = AsyncComputeTaskPool::get().spawn(async move {
-            // Tar directory
-            let mut tar = tar::Builder::new(Vec::new());
-            tar.append_dir_all(path.file_stem().unwrap(), path.clone())
-                .unwrap();
-            let tar_buffer = tar.into_inner().unwrap();
-
-            // XZ archive
-            let tar_xz = XzEncoder::new(tar_buffer.as_slice(), 0).into_inner();
-
-            // Encrypt archive
-            let mut crypter =
-                crypto::aes::ecb_encryptor(KeySize::KeySize128, &self.key[..16], PkcsPadding);
-            let mut encrypted = vec![];
-            let mut buffer = [0; 4096];
-
-            let mut read_buffer = RefReadBuffer::new(tar_xz);
-            let mut write_buffer = RefWriteBuffer::new(&mut buffer);
-            loop {
-                let result = crypter
-                    .encrypt(&mut read_buffer, &mut write_buffer, true)
-                    .expect("Failed to encrypt data!");
-                encrypted.extend(
-                    write_buffer
-                        .take_read_buffer()
-                        .take_remaining()
-                        .iter()
-                        .map(|&i| i),
-                );
-                match result {
-                    BufferResult::BufferUnderflow => break,
-                    BufferResult::BufferOverflow => {}
-                }
-            }
+        let journal_dir = journal_dir(world, &path);
+        spawn_task(
+            world,
+            DirworldTaskKind::LockDoor,
+            self.path,
+            move |progress, cancellation| {
+                let result = check_trusted(signature).and_then(|_| {
+                    lock_door(
+                        &path,
+                        &self.keys,
+                        payload,
+                        &journal_dir,
+                        &progress,
+                        &cancellation,
+                    )
+                });
+                door_operation_commands(result, path, "locking", |command_queue, journal| {
+                    let locked_path = journal.entry().target.clone();
+                    // Write payload
+                    if let Some(payload) = journal.entry().payload.clone() {
+                        command_queue.push(DirworldSaveEntityCommand {
+                            path: locked_path.clone(),
+                            payload,
+                        });
+                    }
+                    command_queue.push(move |world: &mut World| {
+                        finish_journal(journal);
+                        world.send_event(DirworldDoorLocked { path: locked_path });
+                    });
+                })
+            },
+        );
+    }
+}
+
+fn lock_door(
+    path: &Path,
+    keys: &LockKeys,
+    payload: Option<DirworldEntityPayload>,
+    journal_dir: &Path,
+    progress: &DirworldTaskProgress,
+    cancellation: &DirworldCancellationToken,
+) -> Result<DoorJournal, DoorError> {
+    // A fresh payload needs a random UUID, which `Default` does not provide
+    #[allow(clippy::unwrap_or_default)]
+    let mut payload = payload.unwrap_or_else(DirworldEntityPayload::new);
+    let door_key = match keys {
+        LockKeys::Single(key) => new_door_key(key, &mut payload)?,
+        LockKeys::Shared { keys, threshold } => {
+            new_shared_door_key(keys, *threshold, &mut payload)?
+        }
+    };
 
-            let newpath = format!("{}.tar.xz.aes", path.display());
-            fs::write(&newpath, encrypted).unwrap();
-
-            // Remove original folder
-            fs::remove_dir_all(path).unwrap();
-
-            // Insert key hash as payload relationship
-            let key_digest = md5::compute(&self.key[..16]);
-            let mut payload = payload.unwrap_or_else(|| DirworldEntityPayload::new());
-            let relationships = payload.relationships.get_or_insert_default();
-            relationships.insert("key".into(), key_digest.0);
-
-            // Write payload
-            let mut command_queue = CommandQueue::default();
-            command_queue.push(DirworldSaveEntityCommand {
-                path: newpath.into(),
-                payload,
-            });
-            Some(command_queue)
+    // Locked files drop their extensions, which are restored from the payload when unlocked. The
+    // name is recorded for directories too, since it is the name of the room within the archive.
+    let is_dir = path.is_dir();
+    let file_name = path
+        .file_name()
+        .ok_or(DoorError::InvalidPath)?
+        .to_string_lossy()
+        .into_owned();
+    if is_dir {
+        payload.locked_room = Some(LockedRoom {
+            dir_name: file_name,
         });
-        world.resource_mut::<DirworldTasks>().insert(
-            format!("Locking {:?}", self.path.file_name().unwrap()),
-            task,
-        );
+    } else {
+        payload.locked_file = Some(LockedFile { file_name });
+    }
+    let locked_path = locked_door_path(path);
+    if locked_path.exists() {
+        return Err(DoorError::PathExists(locked_path));
     }
+
+    // Tar, compress and encrypt directory or file into a temporary file, then move it into place
+    // and remove the original folder or file, whose payload now belongs to the locked door
+    let journal = run_journaled(
+        journal_dir,
+        JournalEntry {
+            operation: JournalOperation::Lock,
+            phase: JournalPhase::Started,
+            source: path.to_path_buf(),
+            target: locked_path.clone(),
+            temp: temp_path(&locked_path),
+            payload: Some(payload),
+        },
+        cancellation,
+        |temp| write_locked_archive(path, temp, &door_key, progress, cancellation),
+    )?;
+    if !is_dir {
+        remove_sidecar(path);
+    }
+
+    Ok(journal)
 }
 
 struct DirworldUnlockDoorCommand {
     path: PathBuf,
-    key: Vec<u8>,
+    keys: Vec<Vec<u8>>,
+    consumed_key: Option<Entity>,
 }
 
 impl Command for DirworldUnlockDoorCommand {
     fn apply(self, world: &mut World) {
         let path = self.path.clone();
-        // Get existing payload
-        let codecs = world.remove_resource::<DirworldCodecs>().unwrap();
-        let (payload, carrier) = extract_entity_payload(&path, &codecs);
-        world.insert_resource(codecs);
-        let task = AsyncComputeTaskPool::get().spawn(async move {
-            // Decrypt archive
-            let mut decrypter =
-                crypto::aes::ecb_decryptor(KeySize::KeySize128, &self.key[..16], PkcsPadding);
-            let encrypted = carrier.unwrap();
-            let mut decrypted = vec![];
-            let mut buffer = [0; 4096];
-
-            let mut read_buffer = RefReadBuffer::new(&encrypted);
-            let mut write_buffer = RefWriteBuffer::new(&mut buffer);
-            loop {
-                let result = decrypter
-                    .decrypt(&mut read_buffer, &mut write_buffer, true)
-                    .expect("Failed to encrypt data!");
-                decrypted.extend(
-                    write_buffer
-                        .take_read_buffer()
-                        .take_remaining()
-                        .iter()
-                        .map(|&i| i),
-                );
-                match result {
-                    BufferResult::BufferUnderflow => break,
-                    BufferResult::BufferOverflow => {}
+        let codecs = world.resource::<DirworldCodecs>().clone();
+        let migrations = world.resource::<DirworldPayloadMigrations>().clone();
+        let world_key = world.resource::<DirworldWorldKey>().clone();
+        let journal_dir = journal_dir(world, &path);
+        let limits = *world.resource::<DirworldExtractionLimits>();
+        spawn_task(
+            world,
+            DirworldTaskKind::UnlockDoor,
+            self.path,
+            move |progress, cancellation| {
+                // Get existing payload
+                let (payload, carrier, signature) =
+                    extract_entity_payload(&path, &codecs, &migrations, &world_key);
+                let result = check_trusted(signature).and_then(|_| {
+                    unlock_door(
+                        &path,
+                        &self.keys,
+                        payload.as_ref(),
+                        carrier,
+                        &journal_dir,
+                        &limits,
+                        &progress,
+                        &cancellation,
+                    )
+                });
+                door_operation_commands(result, path, "unlocking", |command_queue, journal| {
+                    let unlocked_path = journal.entry().target.clone();
+                    // Write payload
+                    if let Some(payload) = journal.entry().payload.clone() {
+                        command_queue.push(DirworldSaveEntityCommand {
+                            path: unlocked_path.clone(),
+                            payload,
+                        });
+                    }
+                    if let Some(key_entity) = self.consumed_key {
+                        command_queue.push(move |world: &mut World| {
+                            consume_key_item(world, key_entity);
+                        });
+                    }
+                    command_queue.push(move |world: &mut World| {
+                        finish_journal(journal);
+                        world.send_event(DirworldDoorUnlocked {
+                            path: unlocked_path,
+                        });
+                    });
+                })
+            },
+        );
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn unlock_door(
+    path: &Path,
+    keys: &[Vec<u8>],
+    payload: Option<&DirworldEntityPayload>,
+    carrier: Option<Vec<u8>>,
+    journal_dir: &Path,
+    limits: &DirworldExtractionLimits,
+    progress: &DirworldTaskProgress,
+    cancellation: &DirworldCancellationToken,
+) -> Result<DoorJournal, DoorError> {
+    let parent = path.parent().ok_or(DoorError::InvalidPath)?;
+    let recorded_name = payload.map(locked_entry_name).transpose()?.flatten();
+    let name = recorded_name
+        .clone()
+        .or_else(|| path.to_path_buf().file_stem_no_extensions())
+        .ok_or(DoorError::InvalidPath)?;
+    let unlocked_path = parent.join(&name);
+    if unlocked_path.exists() {
+        return Err(DoorError::PathExists(unlocked_path));
+    }
+
+    let tar = open_locked_archive(path, keys, payload, carrier, progress, cancellation)?;
+
+    // Remove key relationship from the payload to be saved once unlocked
+    let mut unlocked_payload = payload.cloned();
+    if let Some(payload) = &mut unlocked_payload {
+        clear_lock(payload);
+    }
+
+    // Safely untar archive into a temporary directory, then move the extracted room into place and
+    // remove the locked file
+    let journal = run_journaled(
+        journal_dir,
+        JournalEntry {
+            operation: JournalOperation::Unlock,
+            phase: JournalPhase::Started,
+            source: path.to_path_buf(),
+            target: unlocked_path.clone(),
+            temp: temp_path(&unlocked_path),
+            payload: unlocked_payload,
+        },
+        cancellation,
+        |temp| {
+            fs::create_dir_all(temp)?;
+            extract_archive(tar, temp, &name, limits)?;
+            // Archives of empty rooms locked by older versions of this crate may not contain the
+            // room itself. Anything else stored under another name would be lost when the
+            // temporary directory is removed.
+            let extracted = temp.join(&name);
+            if !extracted.exists() {
+                if recorded_name.is_some() || fs::read_dir(temp)?.next().is_some() {
+                    return Err(DoorError::MissingEntry(name.clone()));
                 }
+                fs::create_dir(&extracted)?;
             }
+            sync_tree(temp)?;
+            Ok(())
+        },
+    )?;
+    remove_sidecar(path);
 
-            // Unzip archive
-            let tar = XzDecoder::new(decrypted.as_slice()).into_inner();
+    Ok(journal)
+}
 
-            // Untar archive
-            let mut tar = tar::Archive::new(tar);
-            let parent = path.parent().unwrap();
-            tar.unpack(parent).unwrap();
+/// Decrypts and decompresses the archive of a locked door, streaming it from the file if possible
+fn open_locked_archive(
+    path: &Path,
+    keys: &[Vec<u8>],
+    payload: Option<&DirworldEntityPayload>,
+    carrier: Option<Vec<u8>>,
+    progress: &DirworldTaskProgress,
+    cancellation: &DirworldCancellationToken,
+) -> Result<Box<dyn Read>, DoorError> {
+    if is_streamable_door(path)? {
+        let file = File::open(path)?;
+        progress.set_total(file.metadata()?.len());
+        read_locked_archive(keys, payload, BufReader::new(file), progress, cancellation)
+    } else {
+        let encrypted = carrier.ok_or(DoorError::MissingArchive)?;
+        progress.set_total(encrypted.len() as u64);
+        read_locked_archive(
+            keys,
+            payload,
+            Cursor::new(encrypted),
+            progress,
+            cancellation,
+        )
+    }
+}
 
-            fs::remove_file(path.clone()).unwrap();
+struct DirworldRekeyDoorCommand {
+    path: PathBuf,
+    old_keys: Vec<Vec<u8>>,
+    new_key: Vec<u8>,
+}
 
-            if let Some(mut payload) = payload {
-                // Remove key relationship
-                if let Some(ref mut relationships) = payload.relationships {
-                    relationships.remove("key");
-                }
+impl Command for DirworldRekeyDoorCommand {
+    fn apply(self, world: &mut World) {
+        let path = self.path.clone();
+        let codecs = world.resource::<DirworldCodecs>().clone();
+        let migrations = world.resource::<DirworldPayloadMigrations>().clone();
+        let world_key = world.resource::<DirworldWorldKey>().clone();
+        let journal_dir = journal_dir(world, &path);
+        spawn_task(
+            world,
+            DirworldTaskKind::RekeyDoor,
+            self.path,
+            move |progress, cancellation| {
+                // Get existing payload
+                let (payload, carrier, signature) =
+                    extract_entity_payload(&path, &codecs, &migrations, &world_key);
+                let result = check_trusted(signature).and_then(|_| {
+                    rekey_door(
+                        &path,
+                        &self.old_keys,
+                        &self.new_key,
+                        payload,
+                        carrier,
+                        &journal_dir,
+                        &progress,
+                        &cancellation,
+                    )
+                });
+                door_operation_commands(
+                    result,
+                    path.clone(),
+                    "re-keying",
+                    |command_queue, journal| {
+                        let payload = journal.entry().payload.clone();
+                        // Write payload
+                        if let Some(payload) = payload.clone() {
+                            command_queue.push(DirworldSaveEntityCommand {
+                                path: path.clone(),
+                                payload,
+                            });
+                        }
+                        command_queue.push(move |world: &mut World| {
+                            if let Some(payload) = payload {
+                                refresh_locked_door(world, &path, payload);
+                            }
+                            finish_journal(journal);
+                            world.send_event(DirworldDoorRekeyed { path });
+                        });
+                    },
+                )
+            },
+        );
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn rekey_door(
+    path: &Path,
+    old_keys: &[Vec<u8>],
+    new_key: &[u8],
+    payload: Option<DirworldEntityPayload>,
+    carrier: Option<Vec<u8>>,
+    journal_dir: &Path,
+    progress: &DirworldTaskProgress,
+    cancellation: &DirworldCancellationToken,
+) -> Result<DoorJournal, DoorError> {
+    let tar = open_locked_archive(
+        path,
+        old_keys,
+        payload.as_ref(),
+        carrier,
+        progress,
+        cancellation,
+    )?;
+
+    // Replace the lock, keeping the rest of the payload
+    let mut payload = payload.ok_or(DoorError::MissingLock)?;
+    payload.shared_lock = None;
+    let door_key = new_door_key(new_key, &mut payload)?;
+
+    // Re-encrypt archive into a temporary file, then move it into place over the old one
+    run_journaled(
+        journal_dir,
+        JournalEntry {
+            operation: JournalOperation::Rekey,
+            phase: JournalPhase::Started,
+            source: path.to_path_buf(),
+            target: path.to_path_buf(),
+            temp: temp_path(path),
+            payload: Some(payload),
+        },
+        cancellation,
+        |temp| reencrypt_archive(tar, temp, &door_key),
+    )
+}
+
+/// Updates the loaded entity of a locked door after its payload changed
+fn refresh_locked_door(world: &mut World, path: &Path, payload: DirworldEntityPayload) {
+    let mut query = world.query::<(Entity, &mut DirworldEntity)>();
+    let Some(entity) = query
+        .iter_mut(world)
+        .find(|(_, dirworld_entity)| dirworld_entity.path == path)
+        .map(|(entity, mut dirworld_entity)| {
+            // The payload is saved alongside, so this must not mark it unsaved
+            dirworld_entity.bypass_change_detection().payload = Some(payload.clone());
+            entity
+        })
+    else {
+        return;
+    };
+    if let Some(locked_door) = locked_door(path, Some(&payload)) {
+        world.entity_mut(entity).insert(locked_door);
+    }
+}
+
+/// Door operations save the payload they read, signing it with the world key, so they refuse
+/// payloads which failed verification rather than making a player's edits trusted
+fn check_trusted(signature: PayloadSignature) -> Result<(), DoorError> {
+    if signature.is_trusted() {
+        Ok(())
+    } else {
+        Err(DoorError::UntrustedPayload(signature))
+    }
+}
+
+/// Runs a door operation, journaled so a crash can be recovered from. `write` writes the result to
+/// the entry's temporary path, which is then moved into place. If writing fails or the task is
+/// cancelled, the operation is rolled back instead.
+fn run_journaled(
+    journal_dir: &Path,
+    entry: JournalEntry,
+    cancellation: &DirworldCancellationToken,
+    write: impl FnOnce(&Path) -> Result<(), DoorError>,
+) -> Result<DoorJournal, DoorError> {
+    let temp = entry.temp.clone();
+    let mut journal = DoorJournal::begin(journal_dir, entry)?;
+    let result = write(&temp).and_then(|_| cancelled(cancellation));
+    if let Err(e) = result {
+        abandon_journal(journal);
+        cancelled(cancellation)?;
+        return Err(e);
+    }
+
+    journal.set_phase(JournalPhase::Written)?;
+    roll_forward(journal.entry())?;
+    Ok(journal)
+}
 
-                // Write payload
-                let mut command_queue = CommandQueue::default();
-                let new_path = parent.join(path.file_stem_no_extensions().unwrap());
-                let _ = fs::create_dir(new_path.clone());
-                command_queue.push(DirworldSaveEntityCommand {
-                    path: new_path.into(),
-                    payload,
+/// Builds the commands to run once a door operation task ends: those queued by `on_success` if it
+/// succeeded, or events reporting its failure. Cancelled operations have nothing left to do.
+fn door_operation_commands(
+    result: Result<DoorJournal, DoorError>,
+    path: PathBuf,
+    operation: &str,
+    on_success: impl FnOnce(&mut CommandQueue, DoorJournal),
+) -> Option<CommandQueue> {
+    let mut command_queue = CommandQueue::default();
+    match result {
+        Ok(journal) => on_success(&mut command_queue, journal),
+        Err(DoorError::Cancelled) => {
+            info!("Cancelled {operation} {path:?}");
+            return None;
+        }
+        Err(e) => {
+            error!("Failed {operation} {path:?}: {e}");
+            push_failure(&mut command_queue, path, e);
+        }
+    }
+    Some(command_queue)
+}
+
+/// Queues events reporting a failed door operation
+fn push_failure(command_queue: &mut CommandQueue, path: PathBuf, error: DoorError) {
+    let reason = error.to_string();
+    let keys_missing = match error {
+        DoorError::MissingKeys { needed, missing } => Some(DirworldDoorKeysMissing {
+            path: path.clone(),
+            needed,
+            missing,
+        }),
+        _ => None,
+    };
+    command_queue.push(move |world: &mut World| {
+        if let Some(keys_missing) = keys_missing {
+            world.send_event(keys_missing);
+        }
+        world.send_event(DirworldDoorOperationFailed { path, reason });
+    });
+}
+
+/// Directory holding the door journal, inside the world root if one is set
+fn journal_dir(world: &World, path: &Path) -> PathBuf {
+    world
+        .resource::<DirworldRootDir>()
+        .0
+        .clone()
+        .or_else(|| path.parent().map(Path::to_path_buf))
+        .unwrap_or_default()
+        .join(JOURNAL_DIR)
+}
+
+/// Removes a completed operation from the journal
+fn finish_journal(journal: DoorJournal) {
+    let source = journal.entry().source.clone();
+    if let Err(e) = journal.finish() {
+        warn!("Failed to remove journal entry for {source:?}: {e}");
+    }
+}
+
+/// Rolls back a journaled operation which failed before its result was written, and removes it
+/// from the journal
+fn abandon_journal(journal: DoorJournal) {
+    if let Err(e) = roll_back(journal.entry()) {
+        error!("Failed to roll back {:?}: {e}", journal.entry().source);
+        return;
+    }
+    finish_journal(journal);
+}
+
+/// Returns [`DoorError::Cancelled`] if cancellation of the task has been requested
+fn cancelled(cancellation: &DirworldCancellationToken) -> Result<(), DoorError> {
+    if cancellation.is_cancelled() {
+        Err(DoorError::Cancelled)
+    } else {
+        Ok(())
+    }
+}
+
+/// Spawns a background task, registering it in [`DirworldTasks`]
+fn spawn_task(
+    world: &mut World,
+    kind: DirworldTaskKind,
+    path: PathBuf,
+    work: impl FnOnce(DirworldTaskProgress, DirworldCancellationToken) -> Option<CommandQueue>
+        + Send
+        + 'static,
+) {
+    let id = DirworldTaskId::new();
+    let progress = DirworldTaskProgress::default();
+    let cancellation = DirworldCancellationToken::default();
+    let task = {
+        let progress = progress.clone();
+        let cancellation = cancellation.clone();
+        AsyncComputeTaskPool::get().spawn(async move { work(progress, cancellation) })
+    };
+    world.resource_mut::<DirworldTasks>().insert(
+        id,
+        DirworldTask {
+            kind,
+            path: path.clone(),
+            progress,
+            cancellation,
+            task,
+        },
+    );
+    world.send_event(DirworldTaskStarted { id, kind, path });
+}
+
+struct DirworldTryUnlockWithCommand {
+    door: Entity,
+    key_item: Entity,
+    consume: bool,
+}
+
+impl Command for DirworldTryUnlockWithCommand {
+    fn apply(self, world: &mut World) {
+        let Some(door) = world.get::<DirworldEntity>(self.door).cloned() else {
+            warn!(
+                "Tried to unlock {:?}, which is not a dirworld entity",
+                self.door
+            );
+            return;
+        };
+        let passphrase = match world
+            .get::<DirworldEntity>(self.key_item)
+            .ok_or(DoorError::NotAKeyItem)
+            .and_then(|key_item| key_item_passphrase(&door, key_item))
+        {
+            Ok(passphrase) => passphrase,
+            Err(e) => {
+                info!(
+                    "Could not unlock {:?} with {:?}: {e}",
+                    door.path, self.key_item
+                );
+                world.send_event(DirworldDoorOperationFailed {
+                    path: door.path,
+                    reason: e.to_string(),
                 });
-                return Some(command_queue);
+                return;
             }
-            None
-        });
-        world.resource_mut::<DirworldTasks>().insert(
-            format!("Unlocking {:?}", self.path.file_name().unwrap()),
-            task,
-        );
+        };
+
+        DirworldUnlockDoorCommand {
+            path: door.path,
+            keys: vec![passphrase],
+            consumed_key: self.consume.then_some(self.key_item),
+        }
+        .apply(world);
+    }
+}
+
+/// Checks that a key item is meant for a door, by its key relationship, returning the passphrase it
+/// holds. The passphrase itself is only verified once the unlock task derives the door key from
+/// it, as key derivation is too slow for the main thread.
+fn key_item_passphrase(
+    door: &DirworldEntity,
+    key_item: &DirworldEntity,
+) -> Result<Vec<u8>, DoorError> {
+    let key_digest = |entity: &DirworldEntity| {
+        entity
+            .payload
+            .as_ref()
+            .and_then(|payload| payload.relationships.as_ref())
+            .and_then(|relationships| relationships.get(KEY_RELATIONSHIP).copied())
+    };
+
+    // Doors shared between several keys accept the key of any of their slots
+    let mut door_digests: Vec<[u8; 16]> = key_digest(door).into_iter().collect();
+    if let Some(shared_lock) = door
+        .payload
+        .as_ref()
+        .and_then(|payload| payload.shared_lock.as_ref())
+    {
+        door_digests.extend(shared_lock.slots.iter().map(|slot| slot.digest));
+    }
+    if door_digests.is_empty() {
+        return Err(DoorError::NotLocked);
+    }
+    let Some(key_payload) = key_item
+        .payload
+        .as_ref()
+        .filter(|payload| payload.pickup.is_some())
+    else {
+        return Err(DoorError::NotAKeyItem);
+    };
+    let Some(passphrase) = &key_payload.key else {
+        return Err(DoorError::NotAKeyItem);
+    };
+    if !key_digest(key_item).is_some_and(|digest| door_digests.contains(&digest)) {
+        return Err(DoorError::WrongKey);
+    }
+    Ok(passphrase.to_vec())
+}
+
+/// Despawns a key item used to unlock a door and removes its file
+fn consume_key_item(world: &mut World, key_item: Entity) {
+    let Ok(entity) = world.get_entity_mut(key_item) else {
+        return;
+    };
+    if let Some(path) = entity.get::<DirworldEntity>().map(|e| e.path.clone()) {
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("Failed to remove consumed key item {path:?}: {e}");
+        }
+        remove_sidecar(&path);
+    }
+    entity.despawn_recursive();
+}
+
+struct DirworldRecoverJournalCommand {
+    root: PathBuf,
+}
+
+impl Command for DirworldRecoverJournalCommand {
+    fn apply(self, world: &mut World) {
+        // Journals of operations still running are not interrupted, and are finished by their task
+        let running: Vec<PathBuf> = world
+            .resource::<DirworldTasks>()
+            .values()
+            .map(|task| task.path.clone())
+            .collect();
+        for journal in read_journal(&self.root.join(JOURNAL_DIR)) {
+            let entry = journal.entry().clone();
+            if running.contains(&entry.source) {
+                continue;
+            }
+            match entry.phase {
+                JournalPhase::Started => {
+                    info!(
+                        "Rolling back interrupted {:?} of {:?}",
+                        entry.operation, entry.source
+                    );
+                    abandon_journal(journal);
+                }
+                JournalPhase::Written => {
+                    info!(
+                        "Finishing interrupted {:?} of {:?}",
+                        entry.operation, entry.source
+                    );
+                    if let Err(e) = roll_forward(&entry) {
+                        error!("Failed to finish {:?}: {e}", entry.source);
+                        continue;
+                    }
+                    if let Some(payload) = entry.payload {
+                        DirworldSaveEntityCommand {
+                            path: entry.target.clone(),
+                            payload,
+                        }
+                        .apply(world);
+                    }
+                    finish_journal(journal);
+                    match entry.operation {
+                        JournalOperation::Lock => {
+                            world.send_event(DirworldDoorLocked { path: entry.target });
+                        }
+                        JournalOperation::Unlock => {
+                            world.send_event(DirworldDoorUnlocked { path: entry.target });
+                        }
+                        JournalOperation::Rekey => {
+                            world.send_event(DirworldDoorRekeyed { path: entry.target });
+                        }
+                    }
+                }
+            }
+        }
     }
 }
 
@@ -173,108 +738,475 @@ struct DirworldSaveEntityCommand {
     payload: DirworldEntityPayload,
 }
 
-impl Command for DirworldSaveEntityCommand {
+struct DirworldSaveChangesCommand;
+
+impl Command for DirworldSaveChangesCommand {
     fn apply(self, world: &mut World) {
-        info!("Saving {}", &self.path.display());
-        let is_dir = self.path.is_dir();
-        let observers = world.remove_resource::<DirworldObservers>().unwrap();
-        let codecs = world.remove_resource::<DirworldCodecs>().unwrap();
-        let codec = if is_dir {
-            None
-        } else {
-            match codecs.get(&self.path.extensions().unwrap()) {
-                Some(codec) => Some(codec),
-                None => {
-                    warn!(
-                        "No matching codec found for {:?}",
-                        self.path.file_name().unwrap()
-                    );
-                    world.insert_resource(codecs);
-                    world.insert_resource(observers);
-                    return;
-                }
+        let unsaved: Vec<(Entity, PathBuf, Option<DirworldEntityPayload>, bool)> = world
+            .query_filtered::<(Entity, &DirworldEntity, Has<UntrustedPayload>), With<Unsaved>>()
+            .iter(world)
+            .map(|(entity, dirworld_entity, untrusted)| {
+                (
+                    entity,
+                    dirworld_entity.path.clone(),
+                    dirworld_entity.payload.clone(),
+                    untrusted,
+                )
+            })
+            .collect();
+        for (entity, path, payload, untrusted) in unsaved {
+            world.entity_mut(entity).remove::<Unsaved>();
+            // Saving signs the payload, which would make a player's edits trusted
+            if untrusted {
+                warn!("Not saving untrusted payload of {path:?}");
+                continue;
             }
-        };
+            if let Some(payload) = payload {
+                DirworldSaveEntityCommand { path, payload }.apply(world);
+            }
+        }
+    }
+}
 
-        let payload = match rmp_serde::to_vec(&self.payload) {
+impl Command for DirworldSaveEntityCommand {
+    fn apply(mut self, world: &mut World) {
+        info!("Saving {}", &self.path.display());
+        // Referenced sources stay in their own files
+        self.payload.clear_referenced_sources();
+        let world_key = world.resource::<DirworldWorldKey>();
+        let payload = match self.payload.to_signed_bytes(world_key.as_deref()) {
             Ok(payload) => payload,
             Err(e) => {
                 error!("{e:?}");
-                world.insert_resource(codecs);
-                world.insert_resource(observers);
                 return;
             }
         };
 
-        if is_dir {
-            let target_path = self.path.join(".door");
-            if let Err(e) = fs::write(target_path, payload) {
-                error!("{e:?}");
+        if self.path.is_dir() {
+            // Directories authored with a readable payload keep it readable
+            let door_path = self.path.join(".door");
+            let readable_path = self.path.join(READABLE_DOOR_FILE);
+            if readable_path.exists() && !door_path.exists() {
+                match self.payload.to_ron() {
+                    Ok(text) => match write_durably(&readable_path, text.as_bytes()) {
+                        Ok(()) => self.report(world, text.len(), None),
+                        Err(e) => error!("{e:?}"),
+                    },
+                    Err(e) => error!("{e}"),
+                }
+            } else {
+                match write_durably(&door_path, &payload) {
+                    Ok(()) => self.report(world, payload.len(), None),
+                    Err(e) => error!("{e:?}"),
+                }
             }
+            return;
+        }
+
+        // Locked doors which are streamed keep their payload in their sidecar file, so saving it
+        // never reads or rewrites their archive. Other files are only read if they may have codecs.
+        let extensions = self.path.extensions().unwrap_or_default();
+        let codecs = world.resource::<DirworldCodecs>();
+        let streamable_door =
+            is_locked_door(&self.path) && is_streamable_door(&self.path).unwrap_or(false);
+        let carrier = if streamable_door || !codecs.has_file_codecs(&extensions) {
+            None
         } else {
-            let codec = codec.unwrap();
-            let carrier = match fs::read(&self.path) {
-                Ok(raw_carrier) => match codec.decode(&raw_carrier) {
-                    Ok((carrier, _)) => carrier,
-                    Err(e) => match e {
-                        Error::DependencyError(_) => {
-                            error!("{e:?}");
-                            world.insert_resource(codecs);
-                            world.insert_resource(observers);
-                            return;
-                        }
-                        _ => raw_carrier,
-                    },
-                },
+            match fs::read(&self.path) {
+                Ok(raw_carrier) => {
+                    Some((codecs.file_codecs(&extensions, &raw_carrier), raw_carrier))
+                }
                 Err(e) => {
                     error!("{e:?}");
-                    world.insert_resource(codecs);
-                    world.insert_resource(observers);
                     return;
                 }
-            };
+            }
+        };
+        let encoded = match carrier {
+            Some((codecs, raw_carrier)) if !codecs.is_empty() => {
+                Some(embed_payload(&codecs, raw_carrier, &payload))
+            }
+            _ => {
+                info!(
+                    "No matching codec found for {:?}, saving payload to sidecar file",
+                    self.path.file_name().unwrap()
+                );
+                None
+            }
+        };
+        let encoded = match encoded {
+            Some(Ok(encoded)) => Some(encoded),
+            Some(Err(e)) => {
+                warn!("Error encoding payload, falling back to sidecar file: {e}");
+                if let PayloadError::PayloadTooLarge { needed, available } = e {
+                    world.send_event(DirworldPayloadTooLarge {
+                        path: self.path.clone(),
+                        needed,
+                        available,
+                    });
+                }
+                None
+            }
+            None => None,
+        };
 
-            let encoded = match codec.encode(&carrier, &payload) {
-                Ok(encoded) => encoded,
-                Err(e) => {
-                    error!("Error encoding payload: {e:?}");
-                    world.insert_resource(codecs);
-                    world.insert_resource(observers);
+        match encoded {
+            Some((encoded, capacity)) => {
+                if let Err(e) = write_durably(&self.path, &encoded) {
+                    error!("{e:?}");
                     return;
                 }
-            };
-            if let Err(e) = fs::write(&self.path, encoded) {
+                // Embedded payloads take precedence, so a sidecar left from before is stale
+                remove_sidecar(&self.path);
+                self.report(world, payload.len(), Some((encoded.len(), capacity)));
+            }
+            None => match write_durably(&sidecar_path(&self.path), &payload) {
+                Ok(()) => self.report(world, payload.len(), None),
+                Err(e) => error!("{e:?}"),
+            },
+        }
+    }
+}
+
+impl DirworldSaveEntityCommand {
+    /// Report how much space the saved payload takes up, and the size and capacity of the file it
+    /// was embedded in, if any
+    fn report(&self, world: &mut World, size: usize, embedded: Option<(usize, PayloadCapacity)>) {
+        let uncompressed_size = match self.payload.serialized_size() {
+            Ok(uncompressed_size) => uncompressed_size,
+            Err(e) => {
                 error!("{e:?}");
+                return;
             }
+        };
+        match embedded {
+            Some((file_size, capacity)) => info!(
+                "Saved {size} byte payload ({uncompressed_size} bytes uncompressed) into \
+                {file_size} byte file {:?} with capacity {capacity:?}",
+                self.path
+            ),
+            None => info!(
+                "Saved {size} byte payload ({uncompressed_size} bytes uncompressed) for {:?}",
+                self.path
+            ),
         }
+        world.send_event(DirworldPayloadSaved {
+            path: self.path.clone(),
+            size,
+            uncompressed_size,
+            file_size: embedded.map(|(file_size, _)| file_size),
+            capacity: embedded.map(|(_, capacity)| capacity),
+        });
+    }
+}
 
-        world.insert_resource(codecs);
-        world.insert_resource(observers);
+/// Embed a serialized payload into a file with the first of its codecs it fits in, replacing any
+/// payload it already carries, returning the file and the capacity of the codec used. If every
+/// codec fails, their capacities tell whether the payload was too large.
+fn embed_payload(
+    codecs: &[RegisteredCodec],
+    raw_carrier: Vec<u8>,
+    payload: &[u8],
+) -> Result<(Vec<u8>, PayloadCapacity), PayloadError> {
+    let carrier = carrier_without_payload(codecs, raw_carrier)?;
+    let mut error = Error::DataNotEncoded;
+    for codec in codecs {
+        match codec.encode(&carrier, payload) {
+            Ok(encoded) => return Ok((encoded, codec.capacity(&carrier))),
+            Err(e) => error = e,
+        }
+    }
+    match codecs.iter().map(|codec| codec.capacity(&carrier)).max() {
+        Some(PayloadCapacity::Limited(available)) if available < payload.len() => {
+            Err(PayloadError::PayloadTooLarge {
+                needed: payload.len(),
+                available,
+            })
+        }
+        _ => Err(error.into()),
+    }
+}
+
+struct DirworldResolveReferencesCommand {
+    entity: Entity,
+}
+
+impl Command for DirworldResolveReferencesCommand {
+    fn apply(self, world: &mut World) {
+        let Some(dirworld_entity) = world.get::<DirworldEntity>(self.entity) else {
+            return;
+        };
+        let path = dirworld_entity.path.clone();
+        let Some(mut payload) = dirworld_entity.payload.clone() else {
+            return;
+        };
+
+        if let Some(actor) = &mut payload.actor {
+            if let Some(reference) = &actor.yarn_reference {
+                match read_referenced_source(world, &path, reference) {
+                    Ok(source) => actor.yarn_source = source,
+                    Err(reason) => {
+                        report_missing_reference(world, self.entity, &path, reference, reason)
+                    }
+                }
+            }
+        }
+        for script in payload.scripts.iter_mut().flatten() {
+            if let Some(reference) = &script.lua_reference {
+                match read_referenced_source(world, &path, reference) {
+                    Ok(source) => script.lua_source = source,
+                    Err(reason) => {
+                        report_missing_reference(world, self.entity, &path, reference, reason)
+                    }
+                }
+            }
+        }
+
+        // Resolved sources are not changes to the payload, so they must not mark it unsaved
+        if let Some(mut dirworld_entity) = world.get_mut::<DirworldEntity>(self.entity) {
+            dirworld_entity.bypass_change_detection().payload = Some(payload);
+        }
+    }
+}
+
+/// Read the source file a reference in the payload of the entity at the given path points to
+fn read_referenced_source(
+    world: &mut World,
+    entity_path: &Path,
+    reference: &SourceReference,
+) -> Result<Vec<u8>, String> {
+    let root = world
+        .resource::<DirworldRootDir>()
+        .0
+        .clone()
+        .ok_or("No world root is set")?;
+    let source_path = match reference {
+        SourceReference::Root(path) => root.join(path),
+        SourceReference::Relative(path) => entity_path.parent().unwrap_or(&root).join(path),
+        SourceReference::Entity(id) => {
+            let source_path = entity_path_by_id(world, *id, entity_path)
+                .ok_or_else(|| format!("No entity with id {id} is loaded, cached or nearby"))?;
+            if source_path.is_dir() {
+                return Err(format!("Entity {source_path:?} is a directory"));
+            }
+            // Sources are read from the file an entity's payload is embedded in, not the payload
+            let (_, data, _) = extract_entity_payload(
+                &source_path,
+                world.resource(),
+                world.resource(),
+                world.resource(),
+            );
+            return match data {
+                Some(data) => Ok(data),
+                None => fs::read(&source_path).map_err(|e| format!("{source_path:?}: {e}")),
+            };
+        }
+    };
+
+    let source_path = source_path
+        .canonicalize()
+        .map_err(|e| format!("{source_path:?}: {e}"))?;
+    let root = root
+        .canonicalize()
+        .map_err(|e| format!("World root {root:?}: {e}"))?;
+    if !source_path.starts_with(&root) {
+        return Err(format!("{source_path:?} is outside the world root"));
     }
+    fs::read(&source_path).map_err(|e| format!("{source_path:?}: {e}"))
+}
+
+/// Find the path of the dirworld entity whose payload has the given UUID among loaded entities,
+/// cached rooms, and entries next to the entity at `near`, which may not have spawned yet
+fn entity_path_by_id(world: &mut World, id: Uuid, near: &Path) -> Option<PathBuf> {
+    let loaded = world
+        .query::<&DirworldEntity>()
+        .iter(world)
+        .find(|dirworld_entity| {
+            dirworld_entity
+                .payload
+                .as_ref()
+                .is_some_and(|payload| payload.id == id)
+        })
+        .map(|dirworld_entity| dirworld_entity.path.clone());
+    if loaded.is_some() {
+        return loaded;
+    }
+
+    let cached = world
+        .resource::<DirworldCache>()
+        .iter()
+        .find(|(_, payload)| payload.id == id)
+        .map(|(path, _)| path.clone());
+    if cached.is_some() {
+        return cached;
+    }
+
+    fs::read_dir(near.parent()?)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path != near
+                && !path
+                    .file_name()
+                    .is_some_and(|file_name| file_name.to_string_lossy().starts_with("."))
+        })
+        .find(|path| {
+            let (payload, _, _) =
+                extract_entity_payload(path, world.resource(), world.resource(), world.resource());
+            payload.is_some_and(|payload| payload.id == id)
+        })
+}
+
+fn report_missing_reference(
+    world: &mut World,
+    entity: Entity,
+    path: &Path,
+    reference: &SourceReference,
+    reason: String,
+) {
+    error!("Failed to resolve source reference {reference:?} of {path:?}: {reason}");
+    world.send_event(DirworldReferenceMissing {
+        entity,
+        path: path.to_path_buf(),
+        reference: reference.clone(),
+        reason,
+    });
 }
 
 /// Commands for dirworld navigation
 pub trait DirworldCommands {
-    /// Lock Door
+    /// Lock Door with a passphrase of any length. Doors may be directories or single files, such
+    /// as chests; a locked file keeps its payload and is restored with it when unlocked.
     fn dirworld_lock_door(&mut self, path: PathBuf, key: Vec<u8>);
 
-    /// Unlock Door
+    /// Lock Door with up to [`crate::door::MAX_SLOTS`] labelled passphrases, any `threshold` of
+    /// which are needed to unlock it
+    fn dirworld_lock_door_with_keys(
+        &mut self,
+        path: PathBuf,
+        keys: Vec<(String, Vec<u8>)>,
+        threshold: usize,
+    );
+
+    /// Unlock Door with the passphrase it was locked with
     fn dirworld_unlock_door(&mut self, path: PathBuf, key: Vec<u8>);
 
+    /// Unlock Door with a set of passphrases. Doors locked with several keys need at least as many
+    /// of them as their threshold; if too few are given, a
+    /// [`crate::events::DirworldDoorKeysMissing`] event names those still missing.
+    fn dirworld_unlock_door_with_keys(&mut self, path: PathBuf, keys: Vec<Vec<u8>>);
+
+    /// Change the passphrase of a locked door without unlocking it. The archive is re-encrypted
+    /// without being extracted, and the door keeps its payload apart from its lock.
+    fn dirworld_rekey_door(&mut self, path: PathBuf, old_key: Vec<u8>, new_key: Vec<u8>);
+
+    /// Change the passphrase of a locked door without unlocking it, given a set of its current
+    /// passphrases. Doors shared between several keys need at least as many as their threshold,
+    /// and are locked with the single `new_key` afterwards.
+    fn dirworld_rekey_door_with_keys(
+        &mut self,
+        path: PathBuf,
+        old_keys: Vec<Vec<u8>>,
+        new_key: Vec<u8>,
+    );
+
+    /// Try to unlock a locked door entity with a key item entity. The key item's payload must have
+    /// a [`crate::payload::components::Pickup`], a [`crate::payload::components::Key`], and a key
+    /// relationship matching the door's, which is checked before the unlock starts. The key's
+    /// passphrase is checked against the door's lock by the unlock task. If `consume` is set, the
+    /// key item is despawned and its file removed once the door is unlocked.
+    fn dirworld_try_unlock_with(&mut self, door: Entity, key_item: Entity, consume: bool);
+
     /// Save entity
     fn dirworld_save_entity(&mut self, path: PathBuf, payload: DirworldEntityPayload);
+
+    /// Save the payloads of every [`Unsaved`] dirworld entity. This is done automatically
+    /// according to the [`crate::resources::DirworldPersistence`] policy.
+    fn dirworld_save_changes(&mut self);
+
+    /// Read the sources referenced by the [`crate::payload::components::Actor`] and
+    /// [`crate::payload::components::Script`]s of an entity's payload into it. This is done
+    /// automatically when an entity loads; a [`DirworldReferenceMissing`] event is sent for every
+    /// reference which cannot be read.
+    fn dirworld_resolve_references(&mut self, entity: Entity);
+
+    /// Finish or roll back any door operations interrupted by a crash, as recorded in the journal
+    /// in the given world root. Operations of running tasks are left alone. This is done
+    /// automatically whenever the root changes.
+    fn dirworld_recover_journal(&mut self, root: PathBuf);
 }
 
 impl<'w, 's> DirworldCommands for Commands<'w, 's> {
     fn dirworld_lock_door(&mut self, path: PathBuf, key: Vec<u8>) {
-        self.queue(DirworldLockDoorCommand { key, path });
+        self.queue(DirworldLockDoorCommand {
+            keys: LockKeys::Single(key),
+            path,
+        });
+    }
+
+    fn dirworld_lock_door_with_keys(
+        &mut self,
+        path: PathBuf,
+        keys: Vec<(String, Vec<u8>)>,
+        threshold: usize,
+    ) {
+        self.queue(DirworldLockDoorCommand {
+            keys: LockKeys::Shared { keys, threshold },
+            path,
+        });
     }
 
     fn dirworld_unlock_door(&mut self, path: PathBuf, key: Vec<u8>) {
-        self.queue(DirworldUnlockDoorCommand { key, path });
+        self.dirworld_unlock_door_with_keys(path, vec![key]);
+    }
+
+    fn dirworld_unlock_door_with_keys(&mut self, path: PathBuf, keys: Vec<Vec<u8>>) {
+        self.queue(DirworldUnlockDoorCommand {
+            keys,
+            path,
+            consumed_key: None,
+        });
+    }
+
+    fn dirworld_rekey_door(&mut self, path: PathBuf, old_key: Vec<u8>, new_key: Vec<u8>) {
+        self.dirworld_rekey_door_with_keys(path, vec![old_key], new_key);
+    }
+
+    fn dirworld_rekey_door_with_keys(
+        &mut self,
+        path: PathBuf,
+        old_keys: Vec<Vec<u8>>,
+        new_key: Vec<u8>,
+    ) {
+        self.queue(DirworldRekeyDoorCommand {
+            path,
+            old_keys,
+            new_key,
+        });
+    }
+
+    fn dirworld_try_unlock_with(&mut self, door: Entity, key_item: Entity, consume: bool) {
+        self.queue(DirworldTryUnlockWithCommand {
+            door,
+            key_item,
+            consume,
+        });
     }
 
     fn dirworld_save_entity(&mut self, path: PathBuf, payload: DirworldEntityPayload) {
         self.queue(DirworldSaveEntityCommand { path, payload });
     }
+
+    fn dirworld_save_changes(&mut self) {
+        self.queue(DirworldSaveChangesCommand);
+    }
+
+    fn dirworld_resolve_references(&mut self, entity: Entity) {
+        self.queue(DirworldResolveReferencesCommand { entity });
+    }
+
+    fn dirworld_recover_journal(&mut self, root: PathBuf) {
+        self.queue(DirworldRecoverJournalCommand { root });
+    }
 }
@@ -2,7 +2,9 @@ use std::path::PathBuf;
 
 use bevy::prelude::*;
 
-use crate::payload::DirworldEntityPayload;
+use crate::payload::{
+    components::PayloadComponent, DirworldEntityPayload, PayloadError, PayloadSignature,
+};
 
 /// A tooltip on an object, which can be displayed.
 #[derive(Component)]
@@ -17,6 +19,67 @@ pub struct DirworldEntity {
     pub payload: Option<DirworldEntityPayload>,
 }
 
+impl DirworldEntity {
+    /// Get a copy of a custom component from this entity's payload
+    pub fn get_payload_component<T: PayloadComponent>(&self) -> Option<T> {
+        self.payload.as_ref()?.get_component()
+    }
+
+    /// Insert or replace a custom component in this entity's payload, creating the payload if
+    /// needed
+    pub fn set_payload_component<T: PayloadComponent>(
+        &mut self,
+        component: &T,
+    ) -> Result<(), PayloadError> {
+        self.payload
+            .get_or_insert_with(DirworldEntityPayload::new)
+            .set_component(component)
+    }
+
+    /// Remove a custom component from this entity's payload, returning whether there was one
+    pub fn remove_payload_component<T: PayloadComponent>(&mut self) -> bool {
+        self.payload
+            .as_mut()
+            .is_some_and(|payload| payload.remove_component::<T>())
+    }
+}
+
 /// Marker component that prevents an entity from despawning on room change
 #[derive(Debug, Component)]
 pub struct Persist;
+
+/// Component attached to dirworld entities whose payload failed verification against the
+/// [`crate::resources::DirworldWorldKey`], e.g. because a player edited it. Their payloads are
+/// still loaded, but should not be trusted, and are not saved, since saving would sign them.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct UntrustedPayload(pub PayloadSignature);
+
+/// Marker component for dirworld entities whose payload has changed since it was last written to
+/// disk. Removed once the payload is saved according to
+/// [`crate::resources::DirworldPersistence`].
+#[derive(Debug, Component, Clone, Copy, Default)]
+pub struct Unsaved;
+
+/// Component attached to locked doors when they are loaded, so they can be handled without
+/// inspecting their files or payloads
+#[derive(Component, Clone, Debug)]
+pub struct LockedDoor {
+    /// Salted digest of the door's key, matching the key relationship of its key items. Doors
+    /// shared between several keys have none.
+    pub key_digest: Option<[u8; 16]>,
+    /// Labels of the keys of a door shared between several keys
+    pub key_labels: Vec<String>,
+    /// Name of the directory or file the door unlocks into
+    pub original_name: String,
+}
+
+/// Component attached to dirworld entities whose file was sniffed for its content type when loaded,
+/// because no callbacks are registered for its extensions but some are for an
+/// [`crate::resources::EntryType::Content`]
+#[derive(Component, Clone, Debug)]
+pub struct ContentType(pub String);
+
+/// Marker component for entities which can be picked up, inserted from their payload's
+/// [`crate::payload::components::Pickup`]
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct Pickup;
@@ -0,0 +1,63 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+
+/// Number of bytes read from the start of a file to sniff its content type
+pub const SNIFF_LEN: usize = 512;
+
+/// Content type, and the magic bytes expected at each offset in files of that type
+type Signature = (&'static str, &'static [(usize, &'static [u8])]);
+
+/// Magic bytes identifying content types. The first matching signature wins.
+const SIGNATURES: &[Signature] = &[
+    ("image/png", &[(0, b"\x89PNG\r\n\x1a\n")]),
+    ("image/jpeg", &[(0, b"\xff\xd8\xff")]),
+    ("image/gif", &[(0, b"GIF87a")]),
+    ("image/gif", &[(0, b"GIF89a")]),
+    ("image/webp", &[(0, b"RIFF"), (8, b"WEBP")]),
+    ("image/bmp", &[(0, b"BM")]),
+    ("audio/wav", &[(0, b"RIFF"), (8, b"WAVE")]),
+    ("audio/ogg", &[(0, b"OggS")]),
+    ("audio/flac", &[(0, b"fLaC")]),
+    ("audio/mpeg", &[(0, b"ID3")]),
+    ("audio/midi", &[(0, b"MThd")]),
+    ("video/x-msvideo", &[(0, b"RIFF"), (8, b"AVI ")]),
+    ("video/mp4", &[(4, b"ftyp")]),
+    ("video/x-matroska", &[(0, b"\x1a\x45\xdf\xa3")]),
+    ("model/gltf-binary", &[(0, b"glTF")]),
+    ("font/otf", &[(0, b"OTTO")]),
+    ("font/woff", &[(0, b"wOFF")]),
+    ("font/woff2", &[(0, b"wOF2")]),
+    ("application/pdf", &[(0, b"%PDF-")]),
+    ("application/zip", &[(0, b"PK\x03\x04")]),
+    ("application/zip", &[(0, b"PK\x05\x06")]),
+    ("application/gzip", &[(0, b"\x1f\x8b")]),
+    ("application/x-xz", &[(0, b"\xfd7zXZ\x00")]),
+    ("application/x-7z-compressed", &[(0, b"7z\xbc\xaf\x27\x1c")]),
+    ("application/x-tar", &[(257, b"ustar")]),
+];
+
+/// Identify the content type of a file from the magic bytes at its start, e.g. `image/png`. At
+/// least the first [`SNIFF_LEN`] bytes should be given.
+pub fn sniff_content_type(data: &[u8]) -> Option<&'static str> {
+    SIGNATURES
+        .iter()
+        .find(|(_, parts)| {
+            parts.iter().all(|(offset, magic)| {
+                data.get(*offset..)
+                    .is_some_and(|data| data.starts_with(magic))
+            })
+        })
+        .map(|(content_type, _)| *content_type)
+}
+
+/// Identify the content type of the file at the given path from the magic bytes at its start
+pub fn sniff_file_content_type(path: &Path) -> io::Result<Option<&'static str>> {
+    let mut data = Vec::with_capacity(SNIFF_LEN);
+    File::open(path)?
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut data)?;
+    Ok(sniff_content_type(&data))
+}
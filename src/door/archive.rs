@@ -0,0 +1,468 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Cursor, Read, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use tar::EntryType;
+
+use xz2::{read::XzDecoder, write::XzEncoder};
+
+use crate::{
+    payload::DirworldEntityPayload,
+    resources::{DirworldCancellationToken, DirworldExtractionLimits, DirworldTaskProgress},
+};
+
+use super::{
+    decrypt_door, decrypt_legacy_door, door_format_version, door_io_error, unlock_door_key,
+    verify_legacy_key, DoorError, DoorKey, DoorReader, DoorWriter, DOOR_FORMAT_VERSION,
+};
+
+const XZ_PRESET: u32 = 6;
+const DOOR_FORMAT_VERSION_LENGTH: u64 = 5;
+
+/// Archive, compress and encrypt a directory or single file into a locked door file, stored in
+/// the archive under its file name. Data is streamed from the source to the file, so memory use
+/// does not depend on its size. The file is flushed to disk before returning.
+pub fn write_locked_archive(
+    path: &Path,
+    locked_path: &Path,
+    key: &DoorKey,
+    progress: &DirworldTaskProgress,
+    cancellation: &DirworldCancellationToken,
+) -> Result<(), DoorError> {
+    let is_dir = path.is_dir();
+    let name = path.file_name().ok_or(DoorError::InvalidPath)?;
+    progress.set_total(if is_dir {
+        directory_size(path)?
+    } else {
+        fs::metadata(path)?.len()
+    });
+
+    let writer = DoorWriter::new(BufWriter::new(File::create(locked_path)?), key)?;
+    let writer = XzEncoder::new(writer, XZ_PRESET);
+    let mut tar = tar::Builder::new(TaskWriter {
+        inner: writer,
+        progress: progress.clone(),
+        cancellation: cancellation.clone(),
+    });
+    if is_dir {
+        tar.append_dir_all(name, path)?;
+    } else {
+        tar.append_path_with_name(path, name)?;
+    }
+
+    let file = tar.into_inner()?.inner.finish()?.finish()?;
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    Ok(())
+}
+
+/// Compress and encrypt a tar archive read from another locked door into a locked door file, e.g.
+/// to change its key. The file is flushed to disk before returning.
+pub fn reencrypt_archive(
+    mut archive: impl Read,
+    locked_path: &Path,
+    key: &DoorKey,
+) -> Result<(), DoorError> {
+    let writer = DoorWriter::new(BufWriter::new(File::create(locked_path)?), key)?;
+    let mut writer = XzEncoder::new(writer, XZ_PRESET);
+    io::copy(&mut archive, &mut writer)?;
+
+    let file = writer.finish()?.finish()?;
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    Ok(())
+}
+
+/// Open a locked door archive for reading with a passphrase, returning a reader over the
+/// decrypted tar archive. Version 2 archives are decrypted and decompressed as they are read,
+/// while older archives are decrypted in memory.
+///
+/// The progress total should be set to the size of `source` beforehand.
+pub fn read_locked_archive<'a>(
+    passphrases: &[Vec<u8>],
+    payload: Option<&DirworldEntityPayload>,
+    source: impl Read + 'a,
+    progress: &DirworldTaskProgress,
+    cancellation: &DirworldCancellationToken,
+) -> Result<Box<dyn Read + 'a>, DoorError> {
+    let mut source = TaskReader {
+        inner: source,
+        progress: progress.clone(),
+        cancellation: cancellation.clone(),
+    };
+
+    let mut version_bytes = Vec::new();
+    (&mut source)
+        .take(DOOR_FORMAT_VERSION_LENGTH)
+        .read_to_end(&mut version_bytes)?;
+    let version = door_format_version(&version_bytes);
+    let mut source = Cursor::new(version_bytes).chain(source);
+
+    match version {
+        Some(DOOR_FORMAT_VERSION) => {
+            let key = unlock_door_key(passphrases, payload)?;
+            Ok(Box::new(XzDecoder::new(DoorReader::new(source, &key)?)))
+        }
+        Some(1) => {
+            let key = unlock_door_key(passphrases, payload)?;
+            let mut encrypted = Vec::new();
+            source.read_to_end(&mut encrypted)?;
+            Ok(Box::new(Cursor::new(decrypt_door(&key, &encrypted)?)))
+        }
+        Some(version) => Err(DoorError::UnsupportedVersion(version)),
+        None => {
+            let passphrase = passphrases
+                .iter()
+                .find(|passphrase| verify_legacy_key(passphrase, payload).is_ok())
+                .ok_or(DoorError::WrongKey)?;
+            let mut encrypted = Vec::new();
+            source.read_to_end(&mut encrypted)?;
+            Ok(Box::new(Cursor::new(decrypt_legacy_door(
+                passphrase, &encrypted,
+            )?)))
+        }
+    }
+}
+
+/// Extract a decrypted door archive holding the room or file `name` into a directory, validating
+/// every entry first. Entries outside `name`, entries with absolute paths or `..` components,
+/// entries inside or replacing symlinks, links pointing outside `name` or hard links to symlinks,
+/// and special files such as devices are rejected, as are archives exceeding the given limits.
+pub fn extract_archive(
+    archive: impl Read,
+    destination: &Path,
+    name: &str,
+    limits: &DirworldExtractionLimits,
+) -> Result<(), DoorError> {
+    let room = Path::new(name);
+    let mut archive = tar::Archive::new(archive);
+    let mut entries = 0;
+    let mut size = 0u64;
+    let mut symlinks = HashSet::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+
+        entries += 1;
+        if entries > limits.max_entries {
+            return Err(DoorError::TooManyEntries(limits.max_entries));
+        }
+        size = size.saturating_add(entry.header().size()?);
+        if size > limits.max_size {
+            return Err(DoorError::TooLarge(limits.max_size));
+        }
+
+        let unsafe_entry = |reason| DoorError::UnsafeEntry {
+            path: path.clone(),
+            reason,
+        };
+        // Symlinks extracted earlier are never followed, so every entry is written to the path
+        // its name resolves to
+        let resolved = resolve_in_room(Path::new(""), &path, &symlinks)
+            .filter(|resolved| resolved.starts_with(room))
+            .ok_or_else(|| unsafe_entry("path leaves the room or passes through a symlink"))?;
+        if symlinks.contains(&resolved) {
+            return Err(unsafe_entry("path replaces a symlink"));
+        }
+        let entry_type = entry.header().entry_type();
+        match entry_type {
+            EntryType::Regular | EntryType::Continuous | EntryType::Directory => {}
+            EntryType::Symlink | EntryType::Link => {
+                let target = entry
+                    .link_name()?
+                    .ok_or_else(|| unsafe_entry("link has no target"))?;
+                // Symlinks are relative to their own directory, hard links to the archive root
+                let base = match entry_type {
+                    EntryType::Symlink => resolved.parent().unwrap_or(Path::new("")),
+                    _ => Path::new(""),
+                };
+                let target = resolve_in_room(base, &target, &symlinks)
+                    .filter(|target| target.starts_with(room))
+                    .ok_or_else(|| unsafe_entry("link points outside the room"))?;
+                // Hard links to a symlink copy it to another directory, where its target differs
+                if entry_type == EntryType::Link && symlinks.contains(&target) {
+                    return Err(unsafe_entry("hard link points to a symlink"));
+                }
+            }
+            _ => return Err(unsafe_entry("unsupported entry type")),
+        }
+
+        if entry_type == EntryType::Symlink {
+            symlinks.insert(resolved);
+        }
+        entry.unpack_in(destination)?;
+    }
+
+    // A symlink may only leave the room through symlinks extracted after it, so check where each
+    // one leads on disk now that all are in place
+    if symlinks.is_empty() {
+        return Ok(());
+    }
+    let destination = destination.canonicalize()?;
+    let root = destination.join(room);
+    for symlink in symlinks {
+        if !symlink_stays_in_room(&destination, &root, &symlink)? {
+            return Err(DoorError::UnsafeEntry {
+                path: symlink,
+                reason: "link points outside the room",
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Whether a symlink extracted into a directory leads somewhere within the room at `root` on disk.
+/// Targets which do not exist yet are judged by the deepest part of their path which does.
+fn symlink_stays_in_room(destination: &Path, root: &Path, symlink: &Path) -> io::Result<bool> {
+    let link = destination.join(symlink);
+    let target = link
+        .parent()
+        .unwrap_or(destination)
+        .join(fs::read_link(&link)?);
+    for ancestor in target.ancestors() {
+        if let Ok(resolved) = ancestor.canonicalize() {
+            return Ok(resolved.starts_with(root));
+        }
+    }
+    Ok(false)
+}
+
+/// Follow a relative path from a base directory within the room, returning the path within the
+/// room it leads to, or `None` if it leaves the room or passes through one of the given symlinks
+fn resolve_in_room(base: &Path, path: &Path, symlinks: &HashSet<PathBuf>) -> Option<PathBuf> {
+    let mut resolved = PathBuf::new();
+    for component in base.components().chain(path.components()) {
+        match component {
+            Component::CurDir => continue,
+            Component::RootDir | Component::Prefix(_) => return None,
+            Component::Normal(_) | Component::ParentDir => {}
+        }
+        if symlinks.contains(&resolved) {
+            return None;
+        }
+        match component {
+            Component::Normal(name) => resolved.push(name),
+            _ => {
+                if !resolved.pop() {
+                    return None;
+                }
+            }
+        }
+    }
+    Some(resolved)
+}
+
+/// Whether the locked door file at the given path can be decrypted by streaming it straight from
+/// the file
+pub fn is_streamable_door(path: &Path) -> io::Result<bool> {
+    let mut version_bytes = Vec::new();
+    BufReader::new(File::open(path)?)
+        .take(DOOR_FORMAT_VERSION_LENGTH)
+        .read_to_end(&mut version_bytes)?;
+    Ok(door_format_version(&version_bytes) == Some(DOOR_FORMAT_VERSION))
+}
+
+fn directory_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += directory_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Writer which reports progress and stops once its task is cancelled
+struct TaskWriter<W: Write> {
+    inner: W,
+    progress: DirworldTaskProgress,
+    cancellation: DirworldCancellationToken,
+}
+
+impl<W: Write> Write for TaskWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.cancellation.is_cancelled() {
+            return Err(door_io_error(DoorError::Cancelled));
+        }
+        let written = self.inner.write(buf)?;
+        self.progress.add(written as u64);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader which reports progress and stops once its task is cancelled
+struct TaskReader<R: Read> {
+    inner: R,
+    progress: DirworldTaskProgress,
+    cancellation: DirworldCancellationToken,
+}
+
+impl<R: Read> Read for TaskReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.cancellation.is_cancelled() {
+            return Err(door_io_error(DoorError::Cancelled));
+        }
+        let read = self.inner.read(buf)?;
+        self.progress.add(read as u64);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(base: &str, path: &str, symlinks: &[&str]) -> Option<PathBuf> {
+        let symlinks = symlinks.iter().map(PathBuf::from).collect();
+        resolve_in_room(Path::new(base), Path::new(path), &symlinks)
+    }
+
+    #[test]
+    fn resolves_relative_paths() {
+        assert_eq!(resolve("", "room/a", &[]), Some("room/a".into()));
+        assert_eq!(resolve("room", "./a/../b", &[]), Some("room/b".into()));
+        assert_eq!(resolve("room/a", "../..", &[]), Some("".into()));
+    }
+
+    #[test]
+    fn rejects_parent_components_leaving_the_room() {
+        assert_eq!(resolve("", "..", &[]), None);
+        assert_eq!(resolve("", "room/../../a", &[]), None);
+        assert_eq!(resolve("room/a", "../../..", &[]), None);
+    }
+
+    #[test]
+    fn rejects_absolute_paths() {
+        assert_eq!(resolve("", "/etc/passwd", &[]), None);
+        assert_eq!(resolve("room", "/room/a", &[]), None);
+    }
+
+    #[test]
+    fn rejects_paths_through_symlinks() {
+        // `room/d -> .` is harmless by itself, but `room/d/e -> ../..` would then resolve to the
+        // parent of the room's directory
+        assert_eq!(resolve("room", ".", &[]), Some("room".into()));
+        assert_eq!(resolve("", "room/d/e", &["room/d"]), None);
+        assert_eq!(resolve("room/d", "../..", &["room/d"]), None);
+        assert_eq!(resolve("room", "d/../..", &["room/d"]), None);
+        // Links to a symlink itself are followed by the OS, which checks the chain on its own
+        assert_eq!(resolve("room", "d", &["room/d"]), Some("room/d".into()));
+    }
+
+    enum Entry {
+        Directory,
+        Symlink(&'static str),
+        HardLink(&'static str),
+    }
+
+    fn archive(entries: &[(&str, Entry)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, entry) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(0o755);
+            header.set_size(0);
+            match entry {
+                Entry::Directory => {
+                    header.set_entry_type(EntryType::Directory);
+                    builder.append_data(&mut header, path, io::empty()).unwrap();
+                }
+                Entry::Symlink(target) | Entry::HardLink(target) => {
+                    header.set_entry_type(match entry {
+                        Entry::Symlink(_) => EntryType::Symlink,
+                        _ => EntryType::Link,
+                    });
+                    builder.append_link(&mut header, path, target).unwrap();
+                }
+            }
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn extract(name: &str, entries: &[(&str, Entry)]) -> Result<(), DoorError> {
+        let destination =
+            std::env::temp_dir().join(format!("dirworld_extract_{name}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&destination);
+        fs::create_dir_all(&destination)?;
+        let result = extract_archive(
+            Cursor::new(archive(entries)),
+            &destination,
+            "room",
+            &DirworldExtractionLimits::default(),
+        );
+        fs::remove_dir_all(&destination)?;
+        result
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_symlink_chains() {
+        use Entry::*;
+        assert!(extract("inside", &[("room", Directory), ("room/d", Symlink("."))]).is_ok());
+        assert!(matches!(
+            extract(
+                "chain",
+                &[
+                    ("room", Directory),
+                    ("room/d", Symlink(".")),
+                    ("room/d/e", Symlink("../..")),
+                ]
+            ),
+            Err(DoorError::UnsafeEntry { .. })
+        ));
+        assert!(matches!(
+            extract(
+                "late_chain",
+                &[
+                    ("room", Directory),
+                    ("room/e", Symlink("d/../../x")),
+                    ("room/d", Symlink(".")),
+                ]
+            ),
+            Err(DoorError::UnsafeEntry { .. })
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_links_outside_the_room() {
+        use Entry::*;
+        // The extraction directory holds nothing but the room
+        assert!(matches!(
+            extract("sibling", &[("room", Directory), ("other", Directory)]),
+            Err(DoorError::UnsafeEntry { .. })
+        ));
+        assert!(matches!(
+            extract("parent", &[("room", Directory), ("room/a", Symlink(".."))]),
+            Err(DoorError::UnsafeEntry { .. })
+        ));
+        assert!(matches!(
+            extract(
+                "hard_link_to_symlink",
+                &[
+                    ("room", Directory),
+                    ("room/a", Directory),
+                    ("room/a/b", Directory),
+                    ("room/a/b/s", Symlink("..")),
+                    ("room/h", HardLink("room/a/b/s")),
+                ]
+            ),
+            Err(DoorError::UnsafeEntry { .. })
+        ));
+        assert!(matches!(
+            extract(
+                "hard_link_outside",
+                &[("room", Directory), ("room/h", HardLink("other"))]
+            ),
+            Err(DoorError::UnsafeEntry { .. })
+        ));
+    }
+}
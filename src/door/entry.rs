@@ -0,0 +1,112 @@
+use std::path::{Component, Path, PathBuf};
+
+use crate::{components::LockedDoor, payload::DirworldEntityPayload, Extensions};
+
+use super::{DoorError, KEY_RELATIONSHIP};
+
+/// Extensions of locked door files
+pub const LOCKED_DOOR_EXTENSION: &str = "tar.xz.aes";
+
+/// Whether the filesystem entry at the given path is a locked door
+pub fn is_locked_door(path: &Path) -> bool {
+    !path.is_dir()
+        && path
+            .to_path_buf()
+            .extensions()
+            .is_some_and(|extensions| extensions.ends_with(LOCKED_DOOR_EXTENSION))
+}
+
+/// Path of the locked door file a directory or file is locked into
+pub fn locked_door_path(path: &Path) -> PathBuf {
+    let unlocked = if path.is_dir() {
+        path.to_path_buf()
+    } else {
+        path.to_path_buf().no_extensions()
+    };
+    PathBuf::from(format!("{}.{LOCKED_DOOR_EXTENSION}", unlocked.display()))
+}
+
+/// Name the file or directory locked into a door had, and is stored under in its archive, if it
+/// was recorded in the door's payload. Doors locked by older versions of this crate only record
+/// the names of files. Names which are not a single plain path component are rejected, as
+/// unlocking would otherwise write outside the door's directory.
+pub fn locked_entry_name(payload: &DirworldEntityPayload) -> Result<Option<String>, DoorError> {
+    let Some(name) = payload
+        .locked_file
+        .as_ref()
+        .map(|locked_file| &locked_file.file_name)
+        .or_else(|| {
+            payload
+                .locked_room
+                .as_ref()
+                .map(|locked_room| &locked_room.dir_name)
+        })
+    else {
+        return Ok(None);
+    };
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(Some(name.clone())),
+        _ => Err(DoorError::InvalidEntryName(name.clone())),
+    }
+}
+
+/// Build the [`LockedDoor`] component for the filesystem entry at the given path, if it is a
+/// locked door
+pub fn locked_door(path: &Path, payload: Option<&DirworldEntityPayload>) -> Option<LockedDoor> {
+    if !is_locked_door(path) {
+        return None;
+    }
+    let original_name = payload
+        .and_then(|payload| locked_entry_name(payload).ok().flatten())
+        .or_else(|| path.to_path_buf().file_stem_no_extensions())?;
+    let key_digest = payload
+        .and_then(|payload| payload.relationships.as_ref())
+        .and_then(|relationships| relationships.get(KEY_RELATIONSHIP).copied());
+    let key_labels = payload
+        .and_then(|payload| payload.shared_lock.as_ref())
+        .map(|shared_lock| {
+            shared_lock
+                .slots
+                .iter()
+                .map(|slot| slot.label.clone())
+                .collect()
+        })
+        .unwrap_or_default();
+    Some(LockedDoor {
+        key_digest,
+        key_labels,
+        original_name,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::payload::components::LockedRoom;
+
+    use super::*;
+
+    fn payload(dir_name: &str) -> DirworldEntityPayload {
+        DirworldEntityPayload {
+            locked_room: Some(LockedRoom {
+                dir_name: dir_name.into(),
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rejects_entry_names_outside_the_door_directory() {
+        assert_eq!(
+            locked_entry_name(&payload("room")).unwrap(),
+            Some("room".into())
+        );
+        assert_eq!(locked_entry_name(&Default::default()).unwrap(), None);
+        for name in ["", ".", "..", "../room", "a/b", "/room", "./room"] {
+            assert!(matches!(
+                locked_entry_name(&payload(name)),
+                Err(DoorError::InvalidEntryName(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::{fmt::Display, path::PathBuf};
+
+use crate::payload::PayloadSignature;
+
+/// Errors which can occur while locking or unlocking a door
+#[derive(Debug)]
+pub enum DoorError {
+    /// The archive was written by an unknown version of the format
+    UnsupportedVersion(u8),
+    /// The archive is too short to contain a header
+    Truncated,
+    /// The key was wrong, or the archive has been tampered with
+    Decryption,
+    /// The archive could not be encrypted
+    Encryption,
+    /// The provided passphrase does not match the key digest stored in the door's payload
+    WrongKey,
+    /// The door is not locked
+    NotLocked,
+    /// The entity used to unlock a door is not a key item
+    NotAKeyItem,
+    /// Not enough of the keys of a door shared between several keys were provided
+    MissingKeys {
+        /// Number of additional keys needed
+        needed: usize,
+        /// Labels of the keys which were not provided
+        missing: Vec<String>,
+    },
+    /// A door can not be shared between the given number of keys with the given threshold
+    InvalidThreshold,
+    /// The door's payload is missing the parameters needed to derive its key
+    MissingLock,
+    /// The door key could not be derived from the passphrase
+    KeyDerivation(String),
+    /// The door's payload failed verification against the world key, so it must not be re-signed
+    /// by saving it
+    UntrustedPayload(PayloadSignature),
+    /// The door path has no file name or parent directory
+    InvalidPath,
+    /// The encrypted archive could not be read from the door
+    MissingArchive,
+    /// The archive does not hold the room or file being unlocked under its recorded name
+    MissingEntry(String),
+    /// The name recorded for the room or file locked into a door is not a single path component
+    InvalidEntryName(String),
+    /// Locking or unlocking would overwrite an existing file or directory
+    PathExists(PathBuf),
+    /// An archive entry could write outside the room being unlocked, or is of an unsupported type
+    UnsafeEntry {
+        /// Path of the entry within the archive
+        path: PathBuf,
+        /// Why the entry was rejected
+        reason: &'static str,
+    },
+    /// The archive has more entries than [`crate::resources::DirworldExtractionLimits`] allow
+    TooManyEntries(usize),
+    /// The archive extracts to more bytes than [`crate::resources::DirworldExtractionLimits`]
+    /// allow
+    TooLarge(u64),
+    /// The operation was cancelled
+    Cancelled,
+    /// A filesystem operation failed
+    Io(std::io::Error),
+}
+
+impl Display for DoorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DoorError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported door format version: {version}")
+            }
+            DoorError::Truncated => write!(f, "Door archive is truncated"),
+            DoorError::Decryption => {
+                write!(
+                    f,
+                    "Failed to decrypt door archive (wrong key or corrupted archive)"
+                )
+            }
+            DoorError::Encryption => write!(f, "Failed to encrypt door archive"),
+            DoorError::WrongKey => write!(f, "Wrong key"),
+            DoorError::NotLocked => write!(f, "Door is not locked"),
+            DoorError::NotAKeyItem => write!(f, "Not a key item"),
+            DoorError::MissingKeys { needed, missing } => write!(
+                f,
+                "Missing {needed} more key(s), from: {}",
+                missing.join(", ")
+            ),
+            DoorError::InvalidThreshold => write!(f, "Invalid key threshold"),
+            DoorError::MissingLock => write!(f, "Door payload has no lock parameters"),
+            DoorError::KeyDerivation(e) => write!(f, "Failed to derive door key: {e}"),
+            DoorError::UntrustedPayload(signature) => {
+                write!(f, "Door payload failed verification: {signature:?}")
+            }
+            DoorError::InvalidPath => write!(f, "Invalid door path"),
+            DoorError::MissingArchive => write!(f, "Could not read door archive"),
+            DoorError::MissingEntry(name) => write!(f, "Door archive does not contain {name}"),
+            DoorError::InvalidEntryName(name) => write!(f, "Invalid locked entry name {name}"),
+            DoorError::PathExists(path) => write!(f, "{} already exists", path.display()),
+            DoorError::UnsafeEntry { path, reason } => {
+                write!(f, "Unsafe archive entry {}: {reason}", path.display())
+            }
+            DoorError::TooManyEntries(limit) => {
+                write!(f, "Archive has more than {limit} entries")
+            }
+            DoorError::TooLarge(limit) => write!(f, "Archive extracts to more than {limit} bytes"),
+            DoorError::Cancelled => write!(f, "Cancelled"),
+            DoorError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for DoorError {}
+
+impl From<std::io::Error> for DoorError {
+    fn from(value: std::io::Error) -> Self {
+        // Door errors raised inside readers and writers are wrapped in io errors
+        match value.downcast::<DoorError>() {
+            Ok(e) => e,
+            Err(value) => DoorError::Io(value),
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::payload::DirworldEntityPayload;
+
+/// Name of the directory inside the world root which holds the door journal
+pub const JOURNAL_DIR: &str = ".journal";
+
+const JOURNAL_EXTENSION: &str = "journal";
+
+/// Operation recorded in the door journal
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JournalOperation {
+    /// Locking a directory or file into a locked door file
+    Lock,
+    /// Unlocking a locked door file into a directory or file
+    Unlock,
+    /// Re-encrypting a locked door file with a new key
+    Rekey,
+}
+
+/// How far a journaled operation got
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JournalPhase {
+    /// The result is being written to its temporary path. Interrupted operations are rolled back.
+    Started,
+    /// The result has been fully written and flushed to its temporary path. Interrupted operations
+    /// are finished.
+    Written,
+}
+
+/// Entry in the door journal, describing a lock or unlock operation
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JournalEntry {
+    /// Operation being performed
+    pub operation: JournalOperation,
+    /// How far the operation got
+    pub phase: JournalPhase,
+    /// Path being locked or unlocked, removed once the operation is committed
+    pub source: PathBuf,
+    /// Path the result is committed to
+    pub target: PathBuf,
+    /// Temporary path the result is written to before being committed
+    pub temp: PathBuf,
+    /// Payload to save to the target once the operation is committed
+    pub payload: Option<DirworldEntityPayload>,
+}
+
+/// Handle to an entry in the door journal. The entry is kept on disk until [`Self::finish`] is
+/// called, so operations interrupted by a crash can be recovered the next time the world root is
+/// opened.
+#[derive(Debug)]
+pub struct DoorJournal {
+    path: PathBuf,
+    entry: JournalEntry,
+}
+
+impl DoorJournal {
+    /// Record the start of an operation in the journal in the given directory
+    pub fn begin(journal_dir: &Path, entry: JournalEntry) -> io::Result<Self> {
+        fs::create_dir_all(journal_dir)?;
+        let journal = Self {
+            path: journal_dir
+                .join(Uuid::new_v4().to_string())
+                .with_extension(JOURNAL_EXTENSION),
+            entry,
+        };
+        journal.write()?;
+        Ok(journal)
+    }
+
+    /// The journaled operation
+    pub fn entry(&self) -> &JournalEntry {
+        &self.entry
+    }
+
+    /// Record that the operation has reached the given phase
+    pub fn set_phase(&mut self, phase: JournalPhase) -> io::Result<()> {
+        self.entry.phase = phase;
+        self.write()
+    }
+
+    /// Remove the operation from the journal once it is complete
+    pub fn finish(self) -> io::Result<()> {
+        fs::remove_file(&self.path)?;
+        sync_dir(self.path.parent().unwrap_or(Path::new(".")));
+        Ok(())
+    }
+
+    fn write(&self) -> io::Result<()> {
+        let data = rmp_serde::to_vec(&self.entry)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        write_durably(&self.path, &data)
+    }
+}
+
+/// Write a file by writing and flushing a temporary file, then renaming it over the target, so
+/// the target never holds partial contents
+pub fn write_durably(path: &Path, data: &[u8]) -> io::Result<()> {
+    let temp_path = temp_path(path);
+    let mut file = File::create(&temp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    sync_dir(path.parent().unwrap_or(Path::new(".")));
+    Ok(())
+}
+
+/// Hidden temporary path next to the given path, used to write results before committing them
+pub fn temp_path(path: &Path) -> PathBuf {
+    let file_name = path
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{file_name}.tmp"))
+}
+
+/// Read every operation left in the journal in the given directory, e.g. by a crash
+pub fn read_journal(journal_dir: &Path) -> Vec<DoorJournal> {
+    let Ok(entries) = fs::read_dir(journal_dir) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == JOURNAL_EXTENSION)
+        })
+        .filter_map(|path| {
+            let data = fs::read(&path)
+                .inspect_err(|e| warn!("Failed to read journal entry {path:?}: {e}"))
+                .ok()?;
+            let entry = rmp_serde::from_slice(&data)
+                .inspect_err(|e| warn!("Failed to deserialize journal entry {path:?}: {e}"))
+                .ok()?;
+            Some(DoorJournal { path, entry })
+        })
+        .collect()
+}
+
+/// Undo an operation which had not finished writing its result, leaving its source untouched
+pub fn roll_back(entry: &JournalEntry) -> io::Result<()> {
+    remove_path(&entry.temp)
+}
+
+/// Commit an operation whose result has been fully written, moving the result to its target and
+/// removing the source. Safe to call again if interrupted.
+pub fn roll_forward(entry: &JournalEntry) -> io::Result<()> {
+    match entry.operation {
+        JournalOperation::Lock | JournalOperation::Rekey => {
+            if entry.temp.exists() {
+                fs::rename(&entry.temp, &entry.target)?;
+            }
+        }
+        JournalOperation::Unlock => {
+            // The archive is extracted into a temporary directory, which holds the result under
+            // the target's name
+            let extracted = entry
+                .target
+                .file_name()
+                .map(|file_name| entry.temp.join(file_name));
+            if let Some(extracted) = extracted.filter(|extracted| extracted.exists()) {
+                fs::rename(extracted, &entry.target)?;
+            }
+            remove_path(&entry.temp)?;
+        }
+    }
+    if let Some(parent) = entry.target.parent() {
+        sync_dir(parent);
+    }
+
+    // Never remove the source unless the result made it to the target, or if it is the target
+    if entry.target.exists() && entry.source != entry.target {
+        remove_path(&entry.source)?;
+    }
+    Ok(())
+}
+
+/// Flush every file and directory in a tree to disk
+pub fn sync_tree(path: &Path) -> io::Result<()> {
+    if path.is_dir() {
+        for entry in fs::read_dir(path)? {
+            sync_tree(&entry?.path())?;
+        }
+        sync_dir(path);
+    } else {
+        File::open(path)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Flush a directory to disk, so entries renamed into it are durable. This is not supported on
+/// every platform, so failures are ignored.
+fn sync_dir(path: &Path) {
+    if let Ok(dir) = File::open(path) {
+        let _ = dir.sync_all();
+    }
+}
+
+fn remove_path(path: &Path) -> io::Result<()> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else if path.exists() {
+        fs::remove_file(path)
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+use aes_gcm::{
+    aead::{rand_core::RngCore, OsRng},
+    Aes256Gcm, Key,
+};
+use argon2::{Algorithm, Argon2, Params, Version};
+
+use crate::payload::{components::Lock, DirworldEntityPayload};
+
+use super::{legacy_key_digest, shared_door_key, DoorError};
+
+/// Name of the payload relationship which holds the key digest of a locked door
+pub const KEY_RELATIONSHIP: &str = "key";
+
+const CIPHER_KEY_LENGTH: usize = 32;
+const DIGEST_LENGTH: usize = 16;
+
+/// Largest Argon2 memory cost in KiB accepted from a door's payload, so a tampered payload cannot
+/// make unlocking allocate unbounded memory
+pub const MAX_MEMORY_COST: u32 = 1024 * 1024;
+/// Largest Argon2 time cost accepted from a door's payload
+pub const MAX_TIME_COST: u32 = 16;
+/// Largest Argon2 parallelism accepted from a door's payload
+pub const MAX_PARALLELISM: u32 = 16;
+
+/// Key of a locked door, derived from a passphrase
+pub struct DoorKey {
+    cipher_key: Key<Aes256Gcm>,
+    digest: [u8; DIGEST_LENGTH],
+}
+
+impl DoorKey {
+    /// Derive a door key from a passphrase of any length using the parameters in the door's
+    /// [`Lock`]. Parameters above [`MAX_MEMORY_COST`], [`MAX_TIME_COST`] or [`MAX_PARALLELISM`]
+    /// are rejected.
+    pub fn derive(passphrase: &[u8], lock: &Lock) -> Result<Self, DoorError> {
+        if lock.memory_cost > MAX_MEMORY_COST
+            || lock.time_cost > MAX_TIME_COST
+            || lock.parallelism > MAX_PARALLELISM
+        {
+            return Err(DoorError::KeyDerivation(
+                "Key derivation parameters exceed the allowed maximums".into(),
+            ));
+        }
+        let params = Params::new(
+            lock.memory_cost,
+            lock.time_cost,
+            lock.parallelism,
+            Some(CIPHER_KEY_LENGTH + DIGEST_LENGTH),
+        )
+        .map_err(|e| DoorError::KeyDerivation(e.to_string()))?;
+        let mut output = [0; CIPHER_KEY_LENGTH + DIGEST_LENGTH];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase, &lock.salt, &mut output)
+            .map_err(|e| DoorError::KeyDerivation(e.to_string()))?;
+
+        let (cipher_key, digest) = output.split_at(CIPHER_KEY_LENGTH);
+        let mut key = Self {
+            cipher_key: Key::<Aes256Gcm>::default(),
+            digest: [0; DIGEST_LENGTH],
+        };
+        key.cipher_key.copy_from_slice(cipher_key);
+        key.digest.copy_from_slice(digest);
+        Ok(key)
+    }
+
+    /// Salted digest of this key, stored in the door's payload to verify passphrases without
+    /// decrypting the archive
+    pub fn digest(&self) -> [u8; 16] {
+        self.digest
+    }
+
+    /// Create a door key from random secret bytes rather than a passphrase. Such keys are never
+    /// checked against a digest, so theirs is left empty.
+    pub(crate) fn from_secret(secret: [u8; 32]) -> Self {
+        Self {
+            cipher_key: Key::<Aes256Gcm>::from(secret),
+            digest: [0; DIGEST_LENGTH],
+        }
+    }
+
+    pub(crate) fn cipher_key(&self) -> &Key<Aes256Gcm> {
+        &self.cipher_key
+    }
+}
+
+/// Create new lock parameters with a random salt
+pub fn generate_lock() -> Lock {
+    let mut salt = [0; 16];
+    OsRng.fill_bytes(&mut salt);
+    Lock {
+        salt,
+        memory_cost: Params::DEFAULT_M_COST,
+        time_cost: Params::DEFAULT_T_COST,
+        parallelism: Params::DEFAULT_P_COST,
+    }
+}
+
+/// Create a new key for locking a door with a passphrase, storing its lock parameters and key
+/// digest in the door's payload
+pub fn new_door_key(
+    passphrase: &[u8],
+    payload: &mut DirworldEntityPayload,
+) -> Result<DoorKey, DoorError> {
+    let lock = generate_lock();
+    let key = DoorKey::derive(passphrase, &lock)?;
+
+    payload.lock = Some(lock);
+    payload
+        .relationships
+        .get_or_insert_default()
+        .insert(KEY_RELATIONSHIP.into(), key.digest());
+    Ok(key)
+}
+
+/// Derive the key of a locked door from a passphrase, checking it against the key digest in the
+/// door's payload
+pub fn door_key(
+    passphrase: &[u8],
+    payload: Option<&DirworldEntityPayload>,
+) -> Result<DoorKey, DoorError> {
+    let lock = payload
+        .and_then(|payload| payload.lock.as_ref())
+        .ok_or(DoorError::MissingLock)?;
+    let key = DoorKey::derive(passphrase, lock)?;
+    if key_digest(payload).is_some_and(|digest| digest != key.digest()) {
+        return Err(DoorError::WrongKey);
+    }
+    Ok(key)
+}
+
+/// Derive the key of a locked door from a set of passphrases, whether it was locked with a single
+/// passphrase or shared between several
+pub fn unlock_door_key(
+    passphrases: &[Vec<u8>],
+    payload: Option<&DirworldEntityPayload>,
+) -> Result<DoorKey, DoorError> {
+    if let Some(shared_lock) = payload.and_then(|payload| payload.shared_lock.as_ref()) {
+        return shared_door_key(passphrases, shared_lock);
+    }
+
+    let mut result = Err(DoorError::WrongKey);
+    for passphrase in passphrases {
+        result = door_key(passphrase, payload);
+        if !matches!(result, Err(DoorError::WrongKey)) {
+            break;
+        }
+    }
+    result
+}
+
+/// Check a passphrase against the key digest in the payload of a door locked by older versions
+/// of this crate
+pub fn verify_legacy_key(
+    passphrase: &[u8],
+    payload: Option<&DirworldEntityPayload>,
+) -> Result<(), DoorError> {
+    if key_digest(payload).is_some_and(|digest| legacy_key_digest(passphrase) != Some(digest)) {
+        return Err(DoorError::WrongKey);
+    }
+    Ok(())
+}
+
+fn key_digest(payload: Option<&DirworldEntityPayload>) -> Option<[u8; 16]> {
+    payload
+        .and_then(|payload| payload.relationships.as_ref())
+        .and_then(|relationships| relationships.get(KEY_RELATIONSHIP))
+        .copied()
+}
+
+/// Remove the lock parameters and key digest from the payload of an unlocked door
+pub fn clear_lock(payload: &mut DirworldEntityPayload) {
+    payload.lock = None;
+    payload.shared_lock = None;
+    payload.locked_file = None;
+    payload.locked_room = None;
+    if let Some(ref mut relationships) = payload.relationships {
+        relationships.remove(KEY_RELATIONSHIP);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derive_rejects_excessive_parameters() {
+        let lock = generate_lock();
+        for lock in [
+            Lock {
+                memory_cost: u32::MAX,
+                ..lock.clone()
+            },
+            Lock {
+                time_cost: MAX_TIME_COST + 1,
+                ..lock.clone()
+            },
+            Lock {
+                parallelism: MAX_PARALLELISM + 1,
+                ..lock
+            },
+        ] {
+            assert!(matches!(
+                DoorKey::derive(b"crystal", &lock),
+                Err(DoorError::KeyDerivation(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,22 @@
+//! Locked doors, i.e. rooms which have been archived and encrypted with a passphrase
+
+mod error;
+pub use error::*;
+
+mod format;
+pub use format::*;
+
+mod key;
+pub use key::*;
+
+mod shared;
+pub use shared::*;
+
+mod archive;
+pub use archive::*;
+
+mod journal;
+pub use journal::*;
+
+mod entry;
+pub use entry::*;
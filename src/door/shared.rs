@@ -0,0 +1,222 @@
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
+};
+use sharks::{Share, Sharks};
+
+use crate::payload::{
+    components::{KeySlot, SharedLock},
+    DirworldEntityPayload,
+};
+
+use super::{generate_lock, DoorError, DoorKey, MAX_MEMORY_COST, MAX_TIME_COST};
+
+const NONCE_LENGTH: usize = 12;
+
+/// Most keys a door can be shared between. Each passphrase tried against a slot costs a key
+/// derivation, so this bounds the work a tampered payload can cause when unlocking.
+pub const MAX_SLOTS: usize = 16;
+
+/// Largest combined Argon2 memory and time cost of the slots of a door, so trying a passphrase
+/// against all of them costs no more than a single derivation with the largest accepted parameters
+const MAX_TOTAL_COST: u64 = MAX_MEMORY_COST as u64 * MAX_TIME_COST as u64;
+
+/// Create a random door key shared between several passphrases, any `threshold` of which are
+/// needed to unlock the door. Each passphrase is given a label, which is stored in the door's
+/// payload along with its key slot so missing keys can be named.
+pub fn new_shared_door_key(
+    keys: &[(String, Vec<u8>)],
+    threshold: usize,
+    payload: &mut DirworldEntityPayload,
+) -> Result<DoorKey, DoorError> {
+    if threshold == 0 || threshold > keys.len() || keys.len() > MAX_SLOTS {
+        return Err(DoorError::InvalidThreshold);
+    }
+
+    let mut secret = [0; 32];
+    OsRng.fill_bytes(&mut secret);
+    let shares = Sharks(threshold as u8).dealer(&secret);
+
+    let mut slots = Vec::with_capacity(keys.len());
+    for ((label, passphrase), share) in keys.iter().zip(shares) {
+        let lock = generate_lock();
+        let slot_key = DoorKey::derive(passphrase, &lock)?;
+        slots.push(KeySlot {
+            label: label.clone(),
+            lock,
+            digest: slot_key.digest(),
+            share: wrap_share(&slot_key, &Vec::from(&share))?,
+        });
+    }
+
+    payload.shared_lock = Some(SharedLock { threshold, slots });
+    Ok(DoorKey::from_secret(secret))
+}
+
+/// Recover the key of a door locked with [`new_shared_door_key`] from a set of passphrases. If
+/// not enough of the door's keys are provided, [`DoorError::MissingKeys`] lists those still
+/// missing. Slots are only opened until enough shares are recovered, since each passphrase tried
+/// against a slot costs a key derivation.
+pub fn shared_door_key(
+    passphrases: &[Vec<u8>],
+    shared_lock: &SharedLock,
+) -> Result<DoorKey, DoorError> {
+    let threshold = shared_lock.threshold;
+    let slot_count = shared_lock.slots.len();
+    if threshold == 0 || threshold > slot_count || slot_count > MAX_SLOTS {
+        return Err(DoorError::InvalidThreshold);
+    }
+    let total_cost: u64 = shared_lock
+        .slots
+        .iter()
+        .map(|slot| u64::from(slot.lock.memory_cost) * u64::from(slot.lock.time_cost))
+        .sum();
+    if total_cost > MAX_TOTAL_COST {
+        return Err(DoorError::KeyDerivation(
+            "Key derivation parameters of the door's slots exceed the allowed maximum".into(),
+        ));
+    }
+
+    let mut shares = Vec::with_capacity(threshold);
+    let mut missing = Vec::new();
+    for slot in &shared_lock.slots {
+        if shares.len() == threshold {
+            break;
+        }
+        match open_slot(passphrases, slot)? {
+            Some(share) => shares.push(share),
+            None => missing.push(slot.label.clone()),
+        }
+    }
+
+    if shares.len() < threshold {
+        return Err(DoorError::MissingKeys {
+            needed: threshold - shares.len(),
+            missing,
+        });
+    }
+
+    let secret = Sharks(threshold as u8)
+        .recover(&shares)
+        .map_err(|_| DoorError::Decryption)?;
+    let secret = secret.try_into().map_err(|_| DoorError::Decryption)?;
+    Ok(DoorKey::from_secret(secret))
+}
+
+/// Find a passphrase which opens a key slot, returning the slot's share of the door key
+fn open_slot(passphrases: &[Vec<u8>], slot: &KeySlot) -> Result<Option<Share>, DoorError> {
+    for passphrase in passphrases {
+        let slot_key = DoorKey::derive(passphrase, &slot.lock)?;
+        if slot_key.digest() != slot.digest {
+            continue;
+        }
+        let share = unwrap_share(&slot_key, &slot.share)?;
+        return Share::try_from(share.as_slice())
+            .map(Some)
+            .map_err(|_| DoorError::Decryption);
+    }
+    Ok(None)
+}
+
+fn wrap_share(key: &DoorKey, share: &[u8]) -> Result<Vec<u8>, DoorError> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = Aes256Gcm::new(key.cipher_key())
+        .encrypt(&nonce, share)
+        .map_err(|_| DoorError::Encryption)?;
+    let mut wrapped = nonce.to_vec();
+    wrapped.extend(ciphertext);
+    Ok(wrapped)
+}
+
+fn unwrap_share(key: &DoorKey, wrapped: &[u8]) -> Result<Vec<u8>, DoorError> {
+    if wrapped.len() < NONCE_LENGTH {
+        return Err(DoorError::Truncated);
+    }
+    let (nonce, ciphertext) = wrapped.split_at(NONCE_LENGTH);
+    Aes256Gcm::new(key.cipher_key())
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| DoorError::Decryption)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(labels: &[&str]) -> Vec<(String, Vec<u8>)> {
+        labels
+            .iter()
+            .map(|label| (label.to_string(), label.to_lowercase().into_bytes()))
+            .collect()
+    }
+
+    fn shared_lock(payload: &DirworldEntityPayload) -> &SharedLock {
+        payload.shared_lock.as_ref().unwrap()
+    }
+
+    #[test]
+    fn recovers_key_from_threshold_of_passphrases() {
+        let mut payload = DirworldEntityPayload::new();
+        let key = new_shared_door_key(&keys(&["Red", "Green", "Blue"]), 2, &mut payload).unwrap();
+        for passphrases in [
+            vec![b"red".to_vec(), b"blue".to_vec()],
+            vec![b"blue".to_vec(), b"green".to_vec(), b"red".to_vec()],
+        ] {
+            let recovered = shared_door_key(&passphrases, shared_lock(&payload)).unwrap();
+            assert_eq!(recovered.cipher_key(), key.cipher_key());
+        }
+    }
+
+    #[test]
+    fn missing_keys_are_labelled() {
+        let mut payload = DirworldEntityPayload::new();
+        new_shared_door_key(&keys(&["Red", "Green", "Blue"]), 2, &mut payload).unwrap();
+        match shared_door_key(
+            &[b"green".to_vec(), b"wrong".to_vec()],
+            shared_lock(&payload),
+        ) {
+            Err(DoorError::MissingKeys { needed, missing }) => {
+                assert_eq!(needed, 1);
+                assert_eq!(missing, vec!["Red".to_string(), "Blue".to_string()]);
+            }
+            _ => panic!("Expected missing keys"),
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_thresholds() {
+        let mut payload = DirworldEntityPayload::new();
+        for (labels, threshold) in [
+            (keys(&["Red", "Green"]), 0),
+            (keys(&["Red", "Green"]), 3),
+            (keys(&["Red"; MAX_SLOTS + 1]), 1),
+        ] {
+            assert!(matches!(
+                new_shared_door_key(&labels, threshold, &mut payload),
+                Err(DoorError::InvalidThreshold)
+            ));
+        }
+
+        new_shared_door_key(&keys(&["Red", "Green"]), 1, &mut payload).unwrap();
+        let mut tampered = shared_lock(&payload).clone();
+        tampered.threshold = 3;
+        assert!(matches!(
+            shared_door_key(&[b"red".to_vec()], &tampered),
+            Err(DoorError::InvalidThreshold)
+        ));
+    }
+
+    #[test]
+    fn rejects_slots_exceeding_total_cost() {
+        let mut payload = DirworldEntityPayload::new();
+        new_shared_door_key(&keys(&["Red", "Green"]), 1, &mut payload).unwrap();
+        let mut tampered = shared_lock(&payload).clone();
+        for slot in &mut tampered.slots {
+            slot.lock.memory_cost = MAX_MEMORY_COST;
+            slot.lock.time_cost = MAX_TIME_COST;
+        }
+        assert!(matches!(
+            shared_door_key(&[b"red".to_vec()], &tampered),
+            Err(DoorError::KeyDerivation(_))
+        ));
+    }
+}
@@ -2,6 +2,12 @@ use std::path::PathBuf;
 
 use bevy::prelude::*;
 
+use crate::{
+    codecs::PayloadCapacity,
+    payload::components::SourceReference,
+    resources::{DirworldTaskId, DirworldTaskKind},
+};
+
 /// Events related to activities in the dirworld.
 #[derive(Event)]
 pub enum DirworldNavigationEvent {
@@ -32,3 +38,122 @@ pub struct DirworldChangeRoot(pub PathBuf);
 /// Event called to spawn a dirworld entities
 #[derive(Event, Debug, Deref, DerefMut, Clone, Copy)]
 pub struct DirworldSpawn(pub Entity);
+
+/// Event sent when a door has been locked
+#[derive(Event, Debug, Clone)]
+pub struct DirworldDoorLocked {
+    /// Path of the encrypted archive the door was locked into
+    pub path: PathBuf,
+}
+
+/// Event sent when a door has been unlocked
+#[derive(Event, Debug, Clone)]
+pub struct DirworldDoorUnlocked {
+    /// Path of the unlocked room
+    pub path: PathBuf,
+}
+
+/// Event sent when the key of a locked door has been changed
+#[derive(Event, Debug, Clone)]
+pub struct DirworldDoorRekeyed {
+    /// Path of the re-keyed door
+    pub path: PathBuf,
+}
+
+/// Event sent when locking or unlocking a door fails
+#[derive(Event, Debug, Clone)]
+pub struct DirworldDoorOperationFailed {
+    /// Path of the door which failed to lock or unlock
+    pub path: PathBuf,
+    /// Description of the failure
+    pub reason: String,
+}
+
+/// Event sent when unlocking a door shared between several keys fails because not enough of them
+/// were provided. A [`DirworldDoorOperationFailed`] event is sent alongside it.
+#[derive(Event, Debug, Clone)]
+pub struct DirworldDoorKeysMissing {
+    /// Path of the door which failed to unlock
+    pub path: PathBuf,
+    /// Number of additional keys needed
+    pub needed: usize,
+    /// Labels of the keys which were not provided
+    pub missing: Vec<String>,
+}
+
+/// Event sent when the payload of an entity has been saved, reporting how much space it takes up
+#[derive(Event, Debug, Clone)]
+pub struct DirworldPayloadSaved {
+    /// Path of the entity whose payload was saved
+    pub path: PathBuf,
+    /// Size of the payload as stored, after compression and signing, in bytes
+    pub size: usize,
+    /// Size of the serialized payload before compression, in bytes
+    pub uncompressed_size: usize,
+    /// Size of the file the payload was embedded in with a codec, including the payload, in bytes.
+    /// `None` if the payload was written to a `.door` or sidecar file instead.
+    pub file_size: Option<usize>,
+    /// How many payload bytes the file the payload was embedded in can hold with the codec used,
+    /// out of which the payload takes up `size`. `None` if the payload was written to a `.door` or
+    /// sidecar file instead.
+    pub capacity: Option<PayloadCapacity>,
+}
+
+/// Event sent when a payload is saved to a sidecar file because it does not fit in the file its
+/// codec would embed it in
+#[derive(Event, Debug, Clone)]
+pub struct DirworldPayloadTooLarge {
+    /// Path of the entity whose payload was saved
+    pub path: PathBuf,
+    /// Size of the payload as stored, in bytes
+    pub needed: usize,
+    /// Number of payload bytes the file can hold
+    pub available: usize,
+}
+
+/// Event sent when a source referenced by an entity's payload cannot be read, e.g. because its file
+/// or entity does not exist. The entity keeps whatever source its payload held inline.
+#[derive(Event, Debug, Clone)]
+pub struct DirworldReferenceMissing {
+    /// Entity whose payload holds the reference
+    pub entity: Entity,
+    /// Path of the entity whose payload holds the reference
+    pub path: PathBuf,
+    /// Reference which could not be resolved
+    pub reference: SourceReference,
+    /// Description of the failure
+    pub reason: String,
+}
+
+/// Event sent when a background task is started
+#[derive(Event, Debug, Clone)]
+pub struct DirworldTaskStarted {
+    /// Identifier of the task
+    pub id: DirworldTaskId,
+    /// Kind of work performed by the task
+    pub kind: DirworldTaskKind,
+    /// Path of the filesystem entry the task operates on
+    pub path: PathBuf,
+}
+
+/// Event sent when a background task finishes, whether it succeeded or failed
+#[derive(Event, Debug, Clone)]
+pub struct DirworldTaskFinished {
+    /// Identifier of the task
+    pub id: DirworldTaskId,
+    /// Kind of work performed by the task
+    pub kind: DirworldTaskKind,
+    /// Path of the filesystem entry the task operates on
+    pub path: PathBuf,
+}
+
+/// Event sent when a background task stops after being cancelled
+#[derive(Event, Debug, Clone)]
+pub struct DirworldTaskCancelled {
+    /// Identifier of the task
+    pub id: DirworldTaskId,
+    /// Kind of work performed by the task
+    pub kind: DirworldTaskKind,
+    /// Path of the filesystem entry the task operates on
+    pub path: PathBuf,
+}
@@ -9,12 +9,21 @@ use bevy::{ecs::system::IntoObserverSystem, prelude::*};
 use bevy_mod_scripting::core::{AddScriptApiProvider, AddScriptHost, AddScriptHostHandler, ScriptingPlugin};
 use bevy_mod_scripting::lua::LuaScriptHost;
 use cache::DirworldCache;
-use events::{DirworldChangeRoot, DirworldEnterRoom, DirworldLeaveRoom, DirworldSpawn};
+use events::{
+    DirworldChangeRoot, DirworldDoorKeysMissing, DirworldDoorLocked, DirworldDoorOperationFailed,
+    DirworldDoorRekeyed, DirworldDoorUnlocked, DirworldEnterRoom, DirworldLeaveRoom,
+    DirworldPayloadSaved, DirworldPayloadTooLarge, DirworldReferenceMissing, DirworldSpawn,
+    DirworldTaskCancelled, DirworldTaskFinished, DirworldTaskStarted,
+};
+use codecs::{CapacityCodec, ProbedCodec};
 use occule::Codec;
+use payload::components::{FromComponents, IntoComponents, PayloadComponent};
 use preload::{DirworldPreload, DirworldPreloadPlugin};
 use resources::EntryType;
 use resources::{
-    DirworldCodecs, DirworldCurrentDir, DirworldObservers, DirworldRootDir, DirworldTasks,
+    DirworldCodecs, DirworldComponentMappings, DirworldCurrentDir, DirworldExtractionLimits,
+    DirworldObservers, DirworldPayloadComponents, DirworldPayloadMigrations, DirworldRootDir,
+    DirworldSyncSettings, DirworldTasks, DirworldWorldKey,
 };
 pub use watcher::DirworldWatcherEvent;
 pub use watcher::DirworldWatcherSet;
@@ -46,6 +55,15 @@ pub mod conditionals;
 /// Room/asset preloading
 pub mod preload;
 
+/// Locked door archives
+pub mod door;
+
+/// Built-in codecs for embedding payloads in files
+pub mod codecs;
+
+/// Content type sniffing
+pub mod content;
+
 mod cache;
 
 mod yarnspinner_api;
@@ -78,6 +96,16 @@ impl Plugin for DirworldPlugin {
                 systems::remove_completed_tasks,
                 lua_api::trigger_update,
                 yarnspinner_api::process_commands,
+                (
+                    (
+                        systems::sync_entity_transforms,
+                        systems::sync_mapped_components,
+                    )
+                        .run_if(|settings: Res<DirworldSyncSettings>| settings.enabled),
+                    systems::mark_unsaved,
+                    systems::persist_changes_on_interval,
+                )
+                    .chain(),
             ),
         )
         .add_script_host::<LuaScriptHost<()>>(PostUpdate)
@@ -88,12 +116,30 @@ impl Plugin for DirworldPlugin {
         .init_resource::<DirworldCache>()
         .init_resource::<DirworldCurrentDir>()
         .init_resource::<DirworldTasks>()
+        .init_resource::<DirworldWorldKey>()
+        .init_resource::<DirworldExtractionLimits>()
+        .init_resource::<DirworldSyncSettings>()
+        .init_resource::<DirworldPayloadMigrations>()
+        .init_resource::<DirworldPayloadComponents>()
+        .init_resource::<DirworldComponentMappings>()
         .init_resource::<DirworldObservers>()
         .init_resource::<DirworldCodecs>()
         .add_event::<DirworldEnterRoom>()
         .add_event::<DirworldLeaveRoom>()
         .add_event::<DirworldChangeRoot>()
         .add_event::<DirworldWatcherEvent>()
+        .add_event::<DirworldDoorLocked>()
+        .add_event::<DirworldDoorUnlocked>()
+        .add_event::<DirworldDoorRekeyed>()
+        .add_event::<DirworldDoorOperationFailed>()
+        .add_event::<DirworldDoorKeysMissing>()
+        .add_event::<DirworldPayloadSaved>()
+        .add_event::<DirworldPayloadTooLarge>()
+        .add_event::<DirworldReferenceMissing>()
+        .add_event::<DirworldTaskStarted>()
+        .add_event::<DirworldTaskFinished>()
+        .add_event::<DirworldTaskCancelled>()
+        .add_observer(observers::insert_payload_components)
         .add_observer(observers::navigate_to_room)
         .add_observer(observers::handle_changes)
         .add_observer(observers::change_root)
@@ -153,6 +199,8 @@ pub trait DirworldApp {
     /// Register callbacks to be executed when a file with given [`EntryType`]s is loaded. The
     /// `preload_callback` parameter controls loading assets and is called before spawning any
     /// entities in the room, and the `spawn_callback` handles initializing the spawned entities.
+    /// Callbacks registered for an [`EntryType::Content`] handle files whose extensions have no
+    /// callbacks, by their sniffed content type.
     fn register_dirworld_entry_callbacks<B: Bundle, M, PB: Bundle, PM>(
         &mut self,
         extensions: Vec<EntryType>,
@@ -160,13 +208,81 @@ pub trait DirworldApp {
         spawn_callback: impl IntoObserverSystem<DirworldSpawn, B, M>,
     ) -> &mut Self;
 
+    /// Register a migration of serialized payloads from one schema version to a later one. Payloads
+    /// written by older versions of this crate, or of a game's fork of it, are migrated until they
+    /// reach [`crate::payload::PAYLOAD_SCHEMA_VERSION`] when they are extracted.
+    fn register_payload_migration(
+        &mut self,
+        from: u32,
+        to: u32,
+        migration: impl Fn(&[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>>
+            + Send
+            + Sync
+            + 'static,
+    ) -> &mut Self;
+
+    /// Register a custom payload component type, so it can be stored in
+    /// [`crate::payload::DirworldEntityPayload`]s and accessed through reflection. Components are
+    /// indexed by their type path, so renaming or moving their type orphans existing data.
+    fn register_payload_component<T: PayloadComponent>(&mut self) -> &mut Self;
+
+    /// Map a custom payload component to ECS components, which are inserted whenever an entity
+    /// with it in its payload is spawned
+    fn register_payload_component_mapping<T: PayloadComponent + IntoComponents>(
+        &mut self,
+    ) -> &mut Self;
+
+    /// Sync changes to the ECS components of a custom payload component back into payloads, when
+    /// enabled in [`resources::DirworldSyncSettings`]
+    fn register_payload_component_sync<T: PayloadComponent + FromComponents>(
+        &mut self,
+    ) -> &mut Self;
+
+    /// Opt out of mapping a payload field to and from ECS components, given its mapping key in
+    /// [`resources::DirworldComponentMappings`]
+    fn disable_payload_component_mapping(&mut self, key: &str) -> &mut Self;
+
     /// Register a [`Codec`] to be used to extract [`crate::payload::DirworldEntityPayload`]s from
-    /// files with matching extensions.
+    /// files with matching extensions. Its capacity is found by probing, see
+    /// [`codecs::ProbedCodec`]. Several codecs may be registered for the same extensions, and are
+    /// tried in the order they were registered, so e.g. a lossless codec can be registered before a
+    /// lossy one with more capacity.
     fn register_dirworld_entry_codec<C: Codec + Send + Sync + 'static>(
         &mut self,
         extensions: Vec<String>,
         codec: C,
     ) -> &mut Self;
+
+    /// Register a [`CapacityCodec`], which reports its own capacity, to be used to extract
+    /// [`crate::payload::DirworldEntityPayload`]s from files with matching extensions, after any
+    /// codecs already registered for them
+    fn register_dirworld_entry_codec_with_capacity<C: CapacityCodec + Send + Sync + 'static>(
+        &mut self,
+        extensions: Vec<String>,
+        codec: C,
+    ) -> &mut Self;
+
+    /// Register the built-in [`codecs::TrailerCodec`] for files with matching extensions, which
+    /// carries payloads appended to the end of files whose formats tolerate trailing bytes. As it
+    /// can hold payloads of any size, register it after any other codecs for the same extensions.
+    fn register_dirworld_trailer_codec(&mut self, extensions: Vec<String>) -> &mut Self;
+
+    /// Register a [`Codec`] to be used to extract [`crate::payload::DirworldEntityPayload`]s from
+    /// files with matching content types, sniffed from their contents, after any codecs registered
+    /// for their extensions. Its capacity is found by probing, see [`codecs::ProbedCodec`].
+    fn register_dirworld_content_codec<C: Codec + Send + Sync + 'static>(
+        &mut self,
+        content_types: Vec<String>,
+        codec: C,
+    ) -> &mut Self;
+
+    /// Register a [`CapacityCodec`], which reports its own capacity, to be used to extract
+    /// [`crate::payload::DirworldEntityPayload`]s from files with matching content types
+    fn register_dirworld_content_codec_with_capacity<C: CapacityCodec + Send + Sync + 'static>(
+        &mut self,
+        content_types: Vec<String>,
+        codec: C,
+    ) -> &mut Self;
 }
 
 impl DirworldApp for App {
@@ -195,7 +311,7 @@ impl DirworldApp for App {
         world.flush();
         world
             .resource_mut::<DirworldObservers>()
-            .insert_many(extensions, observer_entity_id);
+            .register(extensions, observer_entity_id);
         self
     }
 
@@ -203,10 +319,89 @@ impl DirworldApp for App {
         &mut self,
         extensions: Vec<String>,
         codec: C,
+    ) -> &mut Self {
+        self.register_dirworld_entry_codec_with_capacity(extensions, ProbedCodec(codec))
+    }
+
+    fn register_dirworld_entry_codec_with_capacity<C: CapacityCodec + Send + Sync + 'static>(
+        &mut self,
+        extensions: Vec<String>,
+        codec: C,
     ) -> &mut Self {
         self.world_mut()
             .resource_mut::<DirworldCodecs>()
-            .insert_many(extensions, Box::new(codec));
+            .register(extensions, codec);
+        self
+    }
+
+    fn register_dirworld_trailer_codec(&mut self, extensions: Vec<String>) -> &mut Self {
+        self.register_dirworld_entry_codec_with_capacity(extensions, codecs::TrailerCodec)
+    }
+
+    fn register_dirworld_content_codec<C: Codec + Send + Sync + 'static>(
+        &mut self,
+        content_types: Vec<String>,
+        codec: C,
+    ) -> &mut Self {
+        self.register_dirworld_content_codec_with_capacity(content_types, ProbedCodec(codec))
+    }
+
+    fn register_dirworld_content_codec_with_capacity<C: CapacityCodec + Send + Sync + 'static>(
+        &mut self,
+        content_types: Vec<String>,
+        codec: C,
+    ) -> &mut Self {
+        self.world_mut()
+            .resource_mut::<DirworldCodecs>()
+            .register_for_content_types(content_types, codec);
+        self
+    }
+
+    fn register_payload_component<T: PayloadComponent>(&mut self) -> &mut Self {
+        self.register_type::<T>();
+        self.world_mut()
+            .resource_mut::<DirworldPayloadComponents>()
+            .register::<T>();
+        self
+    }
+
+    fn register_payload_component_mapping<T: PayloadComponent + IntoComponents>(
+        &mut self,
+    ) -> &mut Self {
+        self.world_mut()
+            .resource_mut::<DirworldComponentMappings>()
+            .register::<T>();
+        self
+    }
+
+    fn register_payload_component_sync<T: PayloadComponent + FromComponents>(
+        &mut self,
+    ) -> &mut Self {
+        self.world_mut()
+            .resource_mut::<DirworldComponentMappings>()
+            .register_sync::<T>();
+        self
+    }
+
+    fn disable_payload_component_mapping(&mut self, key: &str) -> &mut Self {
+        self.world_mut()
+            .resource_mut::<DirworldComponentMappings>()
+            .remove(key);
+        self
+    }
+
+    fn register_payload_migration(
+        &mut self,
+        from: u32,
+        to: u32,
+        migration: impl Fn(&[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>>
+            + Send
+            + Sync
+            + 'static,
+    ) -> &mut Self {
+        self.world_mut()
+            .resource_mut::<DirworldPayloadMigrations>()
+            .register(from, to, migration);
         self
     }
 }
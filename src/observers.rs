@@ -1,4 +1,4 @@
-use std::ops::Deref;
+use std::{ops::Deref, path::Path};
 
 use bevy::prelude::*;
 use notify::{
@@ -7,20 +7,53 @@ use notify::{
 };
 
 use crate::{
-    cache::DirworldCache, components::{DirworldEntity, Persist}, events::{DirworldChangeRoot, DirworldEnterRoom, DirworldLeaveRoom}, preload::{load_entity, PreloadState, RoomAssets}, resources::{
-        DirworldCodecs, DirworldCurrentDir, DirworldObservers, DirworldRootDir,
+    cache::DirworldCache, commands::DirworldCommands, components::{DirworldEntity, Persist, Unsaved, UntrustedPayload}, events::{DirworldChangeRoot, DirworldEnterRoom, DirworldLeaveRoom}, preload::{load_entity, PreloadState, RoomAssets}, resources::{
+        DirworldCodecs, DirworldComponentMappings, DirworldCurrentDir, DirworldObservers, DirworldPayloadMigrations, DirworldPersistence, DirworldRootDir, DirworldSyncSettings, DirworldWorldKey,
     }, utils::{despawn_entity_by_path, extract_entity_payload}, DirworldWatcherEvent
 };
 
-/// On navigation from a room, insert modified payloads into the cache
+/// When a dirworld entity is spawned, insert the ECS components corresponding to its payload
+pub fn insert_payload_components(
+    trigger: Trigger<OnAdd, DirworldEntity>,
+    dirworld_entities: Query<&DirworldEntity>,
+    mappings: Res<DirworldComponentMappings>,
+    mut commands: Commands,
+) {
+    let entity = trigger.entity();
+    let Some(payload) = dirworld_entities
+        .get(entity)
+        .ok()
+        .and_then(|dirworld_entity| dirworld_entity.payload.as_ref())
+    else {
+        return;
+    };
+    let mut entity_commands = commands.entity(entity);
+    for insert in mappings.values().filter_map(|mapping| mapping.insert) {
+        insert(payload, &mut entity_commands);
+    }
+}
+
+/// On navigation from a room, insert modified payloads into the cache, and save them if configured
+/// in [`DirworldSyncSettings`]
 pub fn navigate_from_room(
     trigger: Trigger<DirworldLeaveRoom>,
-    entities: Query<(Entity, Ref<DirworldEntity>), Without<Persist>>,
+    entities: Query<(Entity, Ref<DirworldEntity>, Has<Unsaved>), Without<Persist>>,
+    untrusted: Query<(), With<UntrustedPayload>>,
+    settings: Res<DirworldSyncSettings>,
     mut cache: ResMut<DirworldCache>,
     mut commands: Commands,
     mut event_writer: EventWriter<DirworldLeaveRoom>,
 ) {
-    for (entity, dirworld_entity) in entities.iter() {
+    for (entity, dirworld_entity, unsaved) in entities.iter() {
+        // Saving signs the payload, which would make a player's edits trusted
+        if unsaved
+            && !untrusted.contains(entity)
+            && settings.persistence == DirworldPersistence::OnRoomLeave
+        {
+            if let Some(payload) = &dirworld_entity.payload {
+                commands.dirworld_save_entity(dirworld_entity.path.clone(), payload.clone());
+            }
+        }
         cache.cache_entity(&dirworld_entity);
         commands.entity(entity).despawn_recursive();
     }
@@ -33,6 +66,8 @@ pub fn navigate_to_room(
     mut cache: ResMut<DirworldCache>,
     observers: Res<DirworldObservers>,
     codecs: Res<DirworldCodecs>,
+    migrations: Res<DirworldPayloadMigrations>,
+    world_key: Res<DirworldWorldKey>,
     mut commands: Commands,
     mut event_writer: EventWriter<DirworldEnterRoom>,
     mut current_dir: ResMut<DirworldCurrentDir>,
@@ -41,7 +76,8 @@ pub fn navigate_to_room(
 ) {
     let path = &trigger.event().0;
 
-    let room_payload = extract_entity_payload(&path.join(".door"), &codecs).0;
+    let room_payload =
+        extract_entity_payload(&path.join(".door"), &codecs, &migrations, &world_key).0;
     *current_dir = DirworldCurrentDir {
         path: path.to_path_buf(),
         payload: room_payload,
@@ -80,6 +116,8 @@ pub fn navigate_to_room(
             &entry,
             &mut cache,
             &codecs,
+            &migrations,
+            &world_key,
             &observers,
             &mut commands,
             &mut next_preload_state,
@@ -95,6 +133,8 @@ pub fn handle_changes(
     dirworld_entities: Query<(Entity, &DirworldEntity)>,
     observers: Res<DirworldObservers>,
     codecs: Res<DirworldCodecs>,
+    migrations: Res<DirworldPayloadMigrations>,
+    world_key: Res<DirworldWorldKey>,
     mut cache: ResMut<DirworldCache>,
     mut event_writer: EventWriter<DirworldWatcherEvent>,
     mut next_preload_state: ResMut<NextState<PreloadState>>,
@@ -109,11 +149,17 @@ pub fn handle_changes(
             }
         }
         EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
-            for path in &event.paths {
+            for path in event
+                .paths
+                .iter()
+                .filter(|path| should_load(path, &dirworld_entities))
+            {
                 load_entity(
                     &path,
                     &mut cache,
                     &codecs,
+                    &migrations,
+                    &world_key,
                     &observers,
                     &mut commands,
                     &mut next_preload_state,
@@ -123,15 +169,19 @@ pub fn handle_changes(
         }
         EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
             despawn_entity_by_path(&mut commands, &dirworld_entities, &event.paths[0]);
-            load_entity(
-                &event.paths[1],
-                &mut cache,
-                &codecs,
-                &observers,
-                &mut commands,
-                &mut next_preload_state,
-                &mut room_assets,
-            );
+            if should_load(&event.paths[1], &dirworld_entities) {
+                load_entity(
+                    &event.paths[1],
+                    &mut cache,
+                    &codecs,
+                    &migrations,
+                    &world_key,
+                    &observers,
+                    &mut commands,
+                    &mut next_preload_state,
+                    &mut room_assets,
+                );
+            }
         }
         EventKind::Modify(ModifyKind::Metadata(MetadataKind::Any)) => {
             despawn_entity_by_path(&mut commands, &dirworld_entities, &event.paths[1]);
@@ -139,6 +189,8 @@ pub fn handle_changes(
                 &event.paths[0],
                 &mut cache,
                 &codecs,
+                &migrations,
+                &world_key,
                 &observers,
                 &mut commands,
                 &mut next_preload_state,
@@ -152,6 +204,19 @@ pub fn handle_changes(
     event_writer.send(trigger.event().clone());
 }
 
+/// Whether a path reported by the watcher should be loaded as a new entity. Hidden files are never
+/// entities, and saving a payload renames a hidden temporary file over an entity's existing file,
+/// which must not spawn it again.
+fn should_load(path: &Path, dirworld_entities: &Query<(Entity, &DirworldEntity)>) -> bool {
+    let hidden = path
+        .file_name()
+        .is_some_and(|file_name| file_name.to_string_lossy().starts_with("."));
+    !hidden
+        && !dirworld_entities
+            .iter()
+            .any(|(_, dirworld_entity)| dirworld_entity.path == path)
+}
+
 pub fn change_root(
     trigger: Trigger<DirworldChangeRoot>,
     mut root_dir: ResMut<DirworldRootDir>,
@@ -165,5 +230,8 @@ pub fn change_root(
     info!("Changing Root to {}", new_root.display());
     **root_dir = Some(new_root.to_path_buf());
 
+    // Clean up after any door operations interrupted by a crash before entering the world
+    commands.dirworld_recover_journal(new_root.to_path_buf());
+
     commands.trigger(DirworldEnterRoom(new_root.to_path_buf()));
 }
@@ -1,8 +1,15 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+};
 
 use avian3d::prelude::RigidBody;
-use bevy::prelude::*;
-use serde::{Deserialize, Serialize};
+use bevy::{ecs::system::EntityCommands, prelude::*, reflect::GetTypeRegistration};
+use serde::{
+    de::{self, DeserializeOwned},
+    ser, Deserialize, Deserializer, Serialize, Serializer,
+};
+use uuid::Uuid;
 use yarnspinner::core::YarnValue;
 
 /// Payload component that corresponds to [`bevy::prelude::Transform`]
@@ -10,7 +17,7 @@ use yarnspinner::core::YarnValue;
 pub struct Transform(pub bevy::prelude::Transform);
 
 /// Payload component that represent's an entity's name
-#[derive(Serialize, Deserialize, Clone, Default, Deref, DerefMut, Debug)]
+#[derive(Serialize, Deserialize, Clone, Default, Deref, DerefMut, Debug, PartialEq)]
 pub struct Name(pub String);
 
 /// Payload component that represents a yarnspinner actor
@@ -20,6 +27,10 @@ pub struct Actor {
     pub local_variables: HashMap<String, YarnValue>,
     /// Source for the yarnspinner dialog
     pub yarn_source: Vec<u8>,
+    /// Reference to a file holding the yarnspinner dialog, read into `yarn_source` when the entity
+    /// loads
+    #[serde(default)]
+    pub yarn_reference: Option<SourceReference>,
 }
 
 /// Payload component that represents a character's voice. Uses rustysynth to generate random MIDI
@@ -51,7 +62,7 @@ impl Default for Voice {
 }
 
 /// Payload component that wraps a [`avian3d::prelude::RigidBody`]
-#[derive(Serialize, Deserialize, Clone, Default, Deref, DerefMut, Debug)]
+#[derive(Serialize, Deserialize, Clone, Default, Deref, DerefMut, Debug, PartialEq)]
 pub struct Rigidbody(pub RigidBody);
 
 /// Payload component that represents mesh colliders that will be generated for this entity
@@ -68,6 +79,22 @@ pub struct MeshCollider {
 pub struct Script {
     /// Lua script source
     pub lua_source: Vec<u8>,
+    /// Reference to a file holding the lua script, read into `lua_source` when the entity loads
+    #[serde(default)]
+    pub lua_reference: Option<SourceReference>,
+}
+
+/// Reference to a source file kept outside of a payload, so writers can edit it directly. Files
+/// must be inside the world root.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum SourceReference {
+    /// Path relative to the world root
+    Root(PathBuf),
+    /// Path relative to the directory containing the entity
+    Relative(PathBuf),
+    /// File of another dirworld entity, by the UUID of its payload. The entity must be in the
+    /// current room or a room visited before.
+    Entity(Uuid),
 }
 
 /// Payload component for an arbitrary relationship map, can store 128-bit identifiers indexed by names
@@ -75,5 +102,168 @@ pub struct Script {
 pub struct Relationships(pub HashMap<String, [u8; 16]>);
 
 /// Payload component that indicates that this entity should be able to be picked up
-#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
 pub struct Pickup;
+
+/// Payload component that stores the parameters used to derive the key of a locked door from its
+/// passphrase
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct Lock {
+    /// Random salt for key derivation
+    pub salt: [u8; 16],
+    /// Argon2 memory cost in KiB
+    pub memory_cost: u32,
+    /// Argon2 number of iterations
+    pub time_cost: u32,
+    /// Argon2 degree of parallelism
+    pub parallelism: u32,
+}
+
+/// Payload component for key items, holding the passphrase of the door they unlock
+#[derive(Serialize, Deserialize, Clone, Default, Deref, DerefMut, Debug)]
+pub struct Key(pub Vec<u8>);
+
+/// Payload component for single files locked as doors, e.g. chests
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct LockedFile {
+    /// Name of the file before it was locked, including its extensions
+    pub file_name: String,
+}
+
+/// Payload component for directories locked as doors
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct LockedRoom {
+    /// Name of the directory before it was locked, under which it is stored in the door's archive
+    pub dir_name: String,
+}
+
+/// Payload component for doors whose key is shared between several passphrases, any `threshold`
+/// of which unlock the door
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct SharedLock {
+    /// Number of keys needed to unlock the door
+    pub threshold: usize,
+    /// Key slots, one per passphrase
+    pub slots: Vec<KeySlot>,
+}
+
+/// A passphrase's share of the key of a door locked with a [`SharedLock`]
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct KeySlot {
+    /// Label of the key which opens this slot, e.g. "Blue Crystal"
+    pub label: String,
+    /// Parameters used to derive the slot key from its passphrase
+    pub lock: Lock,
+    /// Salted digest of the slot key
+    pub digest: [u8; 16],
+    /// Share of the door key, encrypted with the slot key
+    pub share: Vec<u8>,
+}
+
+/// Payload components defined outside this crate, serialized and indexed by their type path
+#[derive(Clone, Default, Deref, DerefMut, Debug)]
+pub struct CustomComponents(pub HashMap<String, Vec<u8>>);
+
+impl Serialize for CustomComponents {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if !serializer.is_human_readable() {
+            return serializer.serialize_newtype_struct("CustomComponents", &self.0);
+        }
+        // Components are stored as MessagePack, which human-readable formats such as RON and JSON
+        // show as their values instead, sorted so exports are stable
+        self.iter()
+            .map(|(type_path, data)| {
+                rmp_serde::from_slice::<ron::Value>(data)
+                    .map(|value| (type_path, value))
+                    .map_err(ser::Error::custom)
+            })
+            .collect::<Result<BTreeMap<_, _>, _>>()?
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for CustomComponents {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if !deserializer.is_human_readable() {
+            return HashMap::deserialize(deserializer).map(Self);
+        }
+        HashMap::<String, ron::Value>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(type_path, value)| {
+                rmp_serde::to_vec(&value)
+                    .map(|data| (type_path, data))
+                    .map_err(de::Error::custom)
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Types which can be stored in a payload as custom components. Implemented for any type which can
+/// be reflected and serialized; register them with
+/// [`crate::DirworldApp::register_payload_component`] to access them through reflection.
+pub trait PayloadComponent:
+    Reflect + TypePath + GetTypeRegistration + Serialize + DeserializeOwned
+{
+}
+
+impl<T: Reflect + TypePath + GetTypeRegistration + Serialize + DeserializeOwned> PayloadComponent
+    for T
+{
+}
+
+/// Payload components which become ECS components when their entity is spawned. Register custom
+/// payload components implementing this with
+/// [`crate::DirworldApp::register_payload_component_mapping`].
+pub trait IntoComponents {
+    /// Insert the ECS components corresponding to this payload component
+    fn insert_into(&self, entity: &mut EntityCommands);
+}
+
+/// Payload components which can be read back from the ECS components of their entity, so changes to
+/// them are synced into its payload. Register custom payload components implementing this with
+/// [`crate::DirworldApp::register_payload_component_sync`].
+pub trait FromComponents: Sized {
+    /// Read this payload component from the ECS components of an entity, or `None` if it has none
+    fn from_components(entity: &EntityRef) -> Option<Self>;
+}
+
+impl IntoComponents for Name {
+    fn insert_into(&self, entity: &mut EntityCommands) {
+        entity.insert(bevy::prelude::Name::new(self.0.clone()));
+    }
+}
+
+impl IntoComponents for Rigidbody {
+    fn insert_into(&self, entity: &mut EntityCommands) {
+        entity.insert(self.0);
+    }
+}
+
+impl IntoComponents for Pickup {
+    fn insert_into(&self, entity: &mut EntityCommands) {
+        entity.insert(crate::components::Pickup);
+    }
+}
+
+impl FromComponents for Name {
+    fn from_components(entity: &EntityRef) -> Option<Self> {
+        entity
+            .get::<bevy::prelude::Name>()
+            .map(|name| Self(name.as_str().to_string()))
+    }
+}
+
+impl FromComponents for Rigidbody {
+    fn from_components(entity: &EntityRef) -> Option<Self> {
+        entity.get::<RigidBody>().copied().map(Self)
+    }
+}
+
+impl FromComponents for Pickup {
+    fn from_components(entity: &EntityRef) -> Option<Self> {
+        entity
+            .contains::<crate::components::Pickup>()
+            .then_some(Self)
+    }
+}
@@ -0,0 +1,101 @@
+use std::io::Read;
+
+use xz2::read::{XzDecoder, XzEncoder};
+
+use super::PayloadError;
+
+/// Flag byte for payloads stored as plain MessagePack
+const UNCOMPRESSED: u8 = 0;
+
+/// Flag byte for payloads compressed with xz
+const XZ_COMPRESSED: u8 = 1;
+
+/// xz preset payloads are compressed with. Higher presets barely shrink payloads of this size
+/// further, but need far more memory to compress and decompress.
+const XZ_PRESET: u32 = 6;
+
+/// Largest size payloads are decompressed to, guarding against payloads crafted to exhaust memory
+const MAX_DECOMPRESSED_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Compress serialized payload data, prefixed with a flag byte. Data which does not shrink, such as
+/// small payloads, is stored uncompressed.
+pub(super) fn compress(data: Vec<u8>) -> Result<Vec<u8>, PayloadError> {
+    let mut compressed = vec![XZ_COMPRESSED];
+    XzEncoder::new(data.as_slice(), XZ_PRESET)
+        .read_to_end(&mut compressed)
+        .map_err(PayloadError::Compression)?;
+    if compressed.len() < data.len() + 1 {
+        return Ok(compressed);
+    }
+    let mut uncompressed = vec![UNCOMPRESSED];
+    uncompressed.extend(data);
+    Ok(uncompressed)
+}
+
+/// Decompress serialized payload data prefixed with a flag byte
+pub(super) fn decompress(data: &[u8]) -> Result<Vec<u8>, PayloadError> {
+    let (flag, data) = data.split_first().ok_or(PayloadError::Truncated)?;
+    match *flag {
+        UNCOMPRESSED => Ok(data.to_vec()),
+        XZ_COMPRESSED => {
+            let mut decompressed = Vec::new();
+            XzDecoder::new(data)
+                .take(MAX_DECOMPRESSED_SIZE + 1)
+                .read_to_end(&mut decompressed)
+                .map_err(PayloadError::Compression)?;
+            if decompressed.len() as u64 > MAX_DECOMPRESSED_SIZE {
+                return Err(PayloadError::DecompressedTooLarge);
+            }
+            Ok(decompressed)
+        }
+        flag => Err(PayloadError::UnsupportedCompression(flag)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use xz2::write::XzEncoder;
+
+    use super::*;
+
+    #[test]
+    fn compresses_large_data() {
+        let data = b"dialogue ".repeat(1000);
+        let compressed = compress(data.clone()).unwrap();
+        assert_eq!(compressed[0], XZ_COMPRESSED);
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompress(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn stores_small_data_uncompressed() {
+        let compressed = compress(b"tiny".to_vec()).unwrap();
+        assert_eq!(compressed, b"\0tiny");
+        assert_eq!(decompress(&compressed).unwrap(), b"tiny");
+    }
+
+    #[test]
+    fn rejects_unknown_flags() {
+        assert!(matches!(decompress(&[]), Err(PayloadError::Truncated)));
+        assert!(matches!(
+            decompress(&[2, 0]),
+            Err(PayloadError::UnsupportedCompression(2))
+        ));
+    }
+
+    #[test]
+    fn rejects_data_decompressing_beyond_limit() {
+        let mut encoder = XzEncoder::new(vec![XZ_COMPRESSED], XZ_PRESET);
+        let block = vec![0; 1024 * 1024];
+        for _ in 0..=MAX_DECOMPRESSED_SIZE / block.len() as u64 {
+            encoder.write_all(&block).unwrap();
+        }
+        let compressed = encoder.finish().unwrap();
+        assert!(matches!(
+            decompress(&compressed),
+            Err(PayloadError::DecompressedTooLarge)
+        ));
+    }
+}
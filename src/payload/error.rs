@@ -0,0 +1,123 @@
+use std::fmt::Display;
+
+/// Errors which can occur while reading or writing a payload
+#[derive(Debug)]
+pub enum PayloadError {
+    /// The payload was written with a newer schema version than this crate supports
+    UnsupportedVersion(u32),
+    /// No migration is registered from the given schema version
+    MissingMigration(u32),
+    /// A migration failed
+    Migration {
+        /// Schema version the migration started from
+        from: u32,
+        /// Reason the migration failed
+        reason: String,
+    },
+    /// The payload ended before its header did
+    Truncated,
+    /// The payload is compressed with an unknown method, given by its flag byte
+    UnsupportedCompression(u8),
+    /// The payload could not be compressed or decompressed
+    Compression(std::io::Error),
+    /// The payload decompresses to more data than is allowed
+    DecompressedTooLarge,
+    /// The payload could not be embedded in a file with a codec
+    Codec(occule::Error),
+    /// The payload does not fit in the file it is embedded in
+    PayloadTooLarge {
+        /// Size of the payload in bytes
+        needed: usize,
+        /// Number of payload bytes the file can hold
+        available: usize,
+    },
+    /// A custom component's type is not registered, or the component is not of its registered type
+    UnregisteredComponent(String),
+    /// The payload could not be serialized
+    Encode(rmp_serde::encode::Error),
+    /// The payload could not be deserialized
+    Decode(rmp_serde::decode::Error),
+    /// The payload could not be exported to RON
+    RonEncode(ron::Error),
+    /// The payload could not be imported from RON
+    RonDecode(ron::error::SpannedError),
+    /// The payload could not be exported to or imported from JSON
+    Json(serde_json::Error),
+}
+
+impl Display for PayloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PayloadError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported payload schema version: {version}")
+            }
+            PayloadError::MissingMigration(version) => {
+                write!(f, "No payload migration registered from version {version}")
+            }
+            PayloadError::Migration { from, reason } => {
+                write!(f, "Payload migration from version {from} failed: {reason}")
+            }
+            PayloadError::Truncated => write!(f, "Payload is truncated"),
+            PayloadError::UnsupportedCompression(flag) => {
+                write!(f, "Unsupported payload compression flag: {flag}")
+            }
+            PayloadError::Compression(e) => {
+                write!(f, "Failed to compress or decompress payload: {e}")
+            }
+            PayloadError::DecompressedTooLarge => {
+                write!(f, "Payload decompresses to too much data")
+            }
+            PayloadError::Codec(e) => write!(f, "Failed to embed payload: {e:?}"),
+            PayloadError::PayloadTooLarge { needed, available } => write!(
+                f,
+                "Payload of {needed} bytes does not fit in a carrier holding {available} bytes"
+            ),
+            PayloadError::UnregisteredComponent(type_path) => {
+                write!(f, "Payload component {type_path} is not registered")
+            }
+            PayloadError::Encode(e) => write!(f, "Failed to serialize payload: {e}"),
+            PayloadError::Decode(e) => write!(f, "Failed to deserialize payload: {e}"),
+            PayloadError::RonEncode(e) => write!(f, "Failed to export payload to RON: {e}"),
+            PayloadError::RonDecode(e) => write!(f, "Failed to import payload from RON: {e}"),
+            PayloadError::Json(e) => write!(f, "Failed to convert payload to or from JSON: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for PayloadError {}
+
+impl From<rmp_serde::encode::Error> for PayloadError {
+    fn from(value: rmp_serde::encode::Error) -> Self {
+        PayloadError::Encode(value)
+    }
+}
+
+impl From<rmp_serde::decode::Error> for PayloadError {
+    fn from(value: rmp_serde::decode::Error) -> Self {
+        PayloadError::Decode(value)
+    }
+}
+
+impl From<occule::Error> for PayloadError {
+    fn from(value: occule::Error) -> Self {
+        PayloadError::Codec(value)
+    }
+}
+
+impl From<ron::Error> for PayloadError {
+    fn from(value: ron::Error) -> Self {
+        PayloadError::RonEncode(value)
+    }
+}
+
+impl From<ron::error::SpannedError> for PayloadError {
+    fn from(value: ron::error::SpannedError) -> Self {
+        PayloadError::RonDecode(value)
+    }
+}
+
+impl From<serde_json::Error> for PayloadError {
+    fn from(value: serde_json::Error) -> Self {
+        PayloadError::Json(value)
+    }
+}
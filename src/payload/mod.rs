@@ -1,9 +1,33 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use bevy::prelude::*;
+use components::PayloadComponent;
+
+use crate::resources::{DirworldPayloadComponents, DirworldPayloadMigrations};
+
 /// Payload components
 pub mod components;
 
+mod error;
+pub use error::*;
+
+mod signature;
+pub use signature::*;
+
+mod compression;
+
+/// Version of the payload schema written by this crate. Bump this and register a migration
+/// whenever fields of [`DirworldEntityPayload`] are added, removed or reordered.
+pub const PAYLOAD_SCHEMA_VERSION: u32 = 4;
+
+/// First schema version in which a flag byte marking compression follows the version
+const COMPRESSED_SCHEMA_VERSION: u32 = 3;
+
+/// Magic bytes preceding the schema version of versioned payloads. Payloads written before
+/// versioning start with a MessagePack array instead, and are treated as version 0.
+const PAYLOAD_MAGIC: &[u8; 3] = b"DWP";
+
 /// Payload steganographically embedded into asset files
 #[derive(Serialize, Deserialize, Default, Clone, Debug)]
 pub struct DirworldEntityPayload { 
@@ -27,6 +51,24 @@ pub struct DirworldEntityPayload {
     pub relationships: Option<components::Relationships>,
     /// Pickup information for this entity
     pub pickup: Option<components::Pickup>,
+    /// Key derivation parameters, if this entity is a locked door
+    #[serde(default)]
+    pub lock: Option<components::Lock>,
+    /// Passphrase held by this entity, if it is a key item
+    #[serde(default)]
+    pub key: Option<components::Key>,
+    /// Key slots, if this entity is a door locked with several keys
+    #[serde(default)]
+    pub shared_lock: Option<components::SharedLock>,
+    /// Original file, if this entity is a locked file rather than a locked directory
+    #[serde(default)]
+    pub locked_file: Option<components::LockedFile>,
+    /// Original directory, if this entity is a locked directory
+    #[serde(default)]
+    pub locked_room: Option<components::LockedRoom>,
+    /// Components defined outside this crate
+    #[serde(default)]
+    pub custom: Option<components::CustomComponents>,
 }
 
 impl DirworldEntityPayload {
@@ -37,5 +79,249 @@ impl DirworldEntityPayload {
             ..Default::default()
         }
     }
+
+    /// Get a copy of a custom component, if this payload has one of the given type
+    pub fn get_component<T: PayloadComponent>(&self) -> Option<T> {
+        let data = self.custom.as_ref()?.get(T::type_path())?;
+        rmp_serde::from_slice(data)
+            .inspect_err(|e| warn!("Could not deserialize {}: {e}", T::type_path()))
+            .ok()
+    }
+
+    /// Insert or replace a custom component
+    pub fn set_component<T: PayloadComponent>(
+        &mut self,
+        component: &T,
+    ) -> Result<(), PayloadError> {
+        let data = rmp_serde::to_vec(component)?;
+        self.custom
+            .get_or_insert_with(Default::default)
+            .insert(T::type_path().to_string(), data);
+        Ok(())
+    }
+
+    /// Remove a custom component, returning whether there was one
+    pub fn remove_component<T: PayloadComponent>(&mut self) -> bool {
+        self.custom
+            .as_mut()
+            .is_some_and(|custom| custom.remove(T::type_path()).is_some())
+    }
+
+    /// Get a custom component through reflection, by its type path. The component's type must be
+    /// registered in `components`.
+    pub fn get_reflect(
+        &self,
+        type_path: &str,
+        components: &DirworldPayloadComponents,
+    ) -> Option<Result<Box<dyn Reflect>, PayloadError>> {
+        let data = self.custom.as_ref()?.get(type_path)?;
+        let registration = components.get(type_path)?;
+        Some((registration.deserialize)(data))
+    }
+
+    /// Insert or replace a custom component through reflection. The component's type must be
+    /// registered in `components`.
+    pub fn set_reflect(
+        &mut self,
+        component: &dyn Reflect,
+        components: &DirworldPayloadComponents,
+    ) -> Result<(), PayloadError> {
+        let type_path = component.reflect_type_path();
+        let registration = components
+            .get(type_path)
+            .ok_or_else(|| PayloadError::UnregisteredComponent(type_path.to_string()))?;
+        let data = (registration.serialize)(component)?;
+        self.custom
+            .get_or_insert_with(Default::default)
+            .insert(type_path.to_string(), data);
+        Ok(())
+    }
+
+    /// Whether this payload references sources kept outside of it, which are resolved when it loads
+    pub fn has_source_references(&self) -> bool {
+        self.actor
+            .as_ref()
+            .is_some_and(|actor| actor.yarn_reference.is_some())
+            || self
+                .scripts
+                .iter()
+                .flatten()
+                .any(|script| script.lua_reference.is_some())
+    }
+
+    /// Clear sources resolved from references, which are read from their files again whenever the
+    /// payload loads rather than saved in it
+    pub fn clear_referenced_sources(&mut self) {
+        if let Some(actor) = &mut self.actor {
+            if actor.yarn_reference.is_some() {
+                actor.yarn_source.clear();
+            }
+        }
+        for script in self.scripts.iter_mut().flatten() {
+            if script.lua_reference.is_some() {
+                script.lua_source.clear();
+            }
+        }
+    }
+
+    /// Serialize this payload, prefixed with the current schema version. Large payloads, such as
+    /// those holding dialogue or script sources, are compressed.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, PayloadError> {
+        let mut data = PAYLOAD_MAGIC.to_vec();
+        data.extend(PAYLOAD_SCHEMA_VERSION.to_le_bytes());
+        data.extend(compression::compress(rmp_serde::to_vec(self)?)?);
+        Ok(data)
+    }
+
+    /// Size of this payload once serialized, before compression
+    pub fn serialized_size(&self) -> Result<usize, PayloadError> {
+        Ok(rmp_serde::to_vec(self)?.len())
+    }
+
+    /// Serialize this payload like [`Self::to_bytes`], signed with the world key if there is one
+    pub fn to_signed_bytes(&self, key: Option<&[u8]>) -> Result<Vec<u8>, PayloadError> {
+        let data = self.to_bytes()?;
+        Ok(match key {
+            Some(key) => sign_payload(data, key),
+            None => data,
+        })
+    }
+
+    /// Deserialize a payload like [`Self::from_bytes`], verifying its signature against the world
+    /// key if there is one
+    pub fn from_signed_bytes(
+        data: &[u8],
+        key: Option<&[u8]>,
+        migrations: &DirworldPayloadMigrations,
+    ) -> (PayloadSignature, Result<Self, PayloadError>) {
+        let (signature, data) = verify_payload(data, key);
+        (signature, Self::from_bytes(data, migrations))
+    }
+
+    /// Export this payload as pretty-printed RON, which world authors can diff, review and edit
+    pub fn to_ron(&self) -> Result<String, PayloadError> {
+        Ok(ron::ser::to_string_pretty(
+            self,
+            ron::ser::PrettyConfig::default(),
+        )?)
+    }
+
+    /// Import a payload exported with [`Self::to_ron`]
+    pub fn from_ron(text: &str) -> Result<Self, PayloadError> {
+        Ok(ron::from_str(text)?)
+    }
+
+    /// Export this payload as pretty-printed JSON
+    pub fn to_json(&self) -> Result<String, PayloadError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Import a payload exported with [`Self::to_json`]
+    pub fn from_json(text: &str) -> Result<Self, PayloadError> {
+        Ok(serde_json::from_str(text)?)
+    }
+
+    /// Deserialize a payload, migrating it to the current schema version if it was written by an
+    /// older version of this crate
+    pub fn from_bytes(
+        data: &[u8],
+        migrations: &DirworldPayloadMigrations,
+    ) -> Result<Self, PayloadError> {
+        let (version, data) = match data.strip_prefix(PAYLOAD_MAGIC) {
+            Some(versioned) if versioned.len() >= 4 => {
+                let (version, data) = versioned.split_at(4);
+                (u32::from_le_bytes(version.try_into().unwrap()), data)
+            }
+            _ => (0, data),
+        };
+        if version > PAYLOAD_SCHEMA_VERSION {
+            return Err(PayloadError::UnsupportedVersion(version));
+        }
+        let data = if version >= COMPRESSED_SCHEMA_VERSION {
+            compression::decompress(data)?
+        } else {
+            data.to_vec()
+        };
+        let data = migrations.migrate(version, data)?;
+        Ok(rmp_serde::from_slice(&data)?)
+    }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn versioned(version: u32, data: &[u8]) -> Vec<u8> {
+        let mut versioned = PAYLOAD_MAGIC.to_vec();
+        versioned.extend(version.to_le_bytes());
+        versioned.extend(data);
+        versioned
+    }
+
+    #[test]
+    fn round_trip() {
+        let payload = DirworldEntityPayload::new();
+        let data = payload.to_bytes().unwrap();
+        assert!(data.starts_with(PAYLOAD_MAGIC));
+        let migrations = DirworldPayloadMigrations::default();
+        let read = DirworldEntityPayload::from_bytes(&data, &migrations).unwrap();
+        assert_eq!(read.id, payload.id);
+    }
+
+    #[test]
+    fn reads_unversioned_payloads() {
+        // Written before versioning, without the fields appended since
+        let id = Uuid::new_v4();
+        let data = rmp_serde::to_vec(&(
+            id,
+            components::Transform::default(),
+            None::<u8>,
+            None::<u8>,
+            None::<u8>,
+            None::<u8>,
+            None::<u8>,
+            None::<u8>,
+            None::<u8>,
+            None::<u8>,
+        ))
+        .unwrap();
+        let migrations = DirworldPayloadMigrations::default();
+        let payload = DirworldEntityPayload::from_bytes(&data, &migrations).unwrap();
+        assert_eq!(payload.id, id);
+        assert!(payload.lock.is_none() && payload.locked_room.is_none());
+    }
+
+    #[test]
+    fn applies_registered_migrations() {
+        let payload = DirworldEntityPayload::new();
+        let current = rmp_serde::to_vec(&payload).unwrap();
+        let mut migrations = DirworldPayloadMigrations(Default::default());
+        assert!(matches!(
+            DirworldEntityPayload::from_bytes(&versioned(0, b"old"), &migrations),
+            Err(PayloadError::MissingMigration(0))
+        ));
+        migrations.register(0, PAYLOAD_SCHEMA_VERSION, |_| Err("unreadable".into()));
+        assert!(matches!(
+            DirworldEntityPayload::from_bytes(&versioned(0, b"old"), &migrations),
+            Err(PayloadError::Migration { from: 0, .. })
+        ));
+
+        migrations.register(0, PAYLOAD_SCHEMA_VERSION, move |data| {
+            assert_eq!(data, b"old");
+            Ok(current.clone())
+        });
+        let read = DirworldEntityPayload::from_bytes(&versioned(0, b"old"), &migrations).unwrap();
+        assert_eq!(read.id, payload.id);
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let migrations = DirworldPayloadMigrations::default();
+        let version = PAYLOAD_SCHEMA_VERSION + 1;
+        assert!(matches!(
+            DirworldEntityPayload::from_bytes(&versioned(version, &[]), &migrations),
+            Err(PayloadError::UnsupportedVersion(v)) if v == version
+        ));
+    }
+}
@@ -0,0 +1,126 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Magic bytes preceding the signature of signed payloads
+const SIGNATURE_MAGIC: &[u8; 3] = b"DWS";
+
+/// Length of an HMAC-SHA256 signature
+const SIGNATURE_LEN: usize = 32;
+
+/// Outcome of verifying a payload's signature against the world key
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PayloadSignature {
+    /// No world key is configured, or there was no payload to verify
+    Unchecked,
+    /// The payload was signed with the world key
+    Valid,
+    /// The payload is not signed, e.g. because it was written before a world key was configured
+    Unsigned,
+    /// The payload's signature does not match its contents, e.g. because a player edited it
+    Invalid,
+}
+
+impl PayloadSignature {
+    /// Whether the payload can be trusted, i.e. its signature is valid or was not checked
+    pub fn is_trusted(&self) -> bool {
+        matches!(self, PayloadSignature::Unchecked | PayloadSignature::Valid)
+    }
+}
+
+/// Sign a serialized payload with the world key, prefixing it with an HMAC-SHA256 signature
+pub fn sign_payload(data: Vec<u8>, key: &[u8]) -> Vec<u8> {
+    let mut signed = SIGNATURE_MAGIC.to_vec();
+    signed.extend(payload_mac(key, &data).finalize().into_bytes());
+    signed.extend(data);
+    signed
+}
+
+/// Verify the signature of a serialized payload against the world key, returning the outcome and
+/// the payload without its signature
+pub fn verify_payload<'a>(data: &'a [u8], key: Option<&[u8]>) -> (PayloadSignature, &'a [u8]) {
+    let Some(signed) = data.strip_prefix(SIGNATURE_MAGIC) else {
+        let signature = match key {
+            Some(_) => PayloadSignature::Unsigned,
+            None => PayloadSignature::Unchecked,
+        };
+        return (signature, data);
+    };
+    if signed.len() < SIGNATURE_LEN {
+        let signature = match key {
+            Some(_) => PayloadSignature::Invalid,
+            None => PayloadSignature::Unchecked,
+        };
+        return (signature, &[]);
+    }
+    let (signature, signed) = signed.split_at(SIGNATURE_LEN);
+    let Some(key) = key else {
+        return (PayloadSignature::Unchecked, signed);
+    };
+    match payload_mac(key, signed).verify_slice(signature) {
+        Ok(()) => (PayloadSignature::Valid, signed),
+        Err(_) => (PayloadSignature::Invalid, signed),
+    }
+}
+
+fn payload_mac(key: &[u8], data: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"world key";
+
+    #[test]
+    fn verifies_signed_payloads() {
+        let signed = sign_payload(b"payload".to_vec(), KEY);
+        assert_eq!(
+            verify_payload(&signed, Some(KEY)),
+            (PayloadSignature::Valid, &b"payload"[..])
+        );
+        assert_eq!(
+            verify_payload(&signed, None),
+            (PayloadSignature::Unchecked, &b"payload"[..])
+        );
+    }
+
+    #[test]
+    fn rejects_tampered_payloads() {
+        let mut signed = sign_payload(b"payload".to_vec(), KEY);
+        *signed.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            verify_payload(&signed, Some(KEY)).0,
+            PayloadSignature::Invalid
+        );
+        let signed = sign_payload(b"payload".to_vec(), b"other key");
+        assert_eq!(
+            verify_payload(&signed, Some(KEY)).0,
+            PayloadSignature::Invalid
+        );
+    }
+
+    #[test]
+    fn reports_unsigned_payloads() {
+        assert_eq!(
+            verify_payload(b"payload", Some(KEY)),
+            (PayloadSignature::Unsigned, &b"payload"[..])
+        );
+        assert_eq!(
+            verify_payload(b"payload", None),
+            (PayloadSignature::Unchecked, &b"payload"[..])
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_signatures() {
+        let signed = sign_payload(Vec::new(), KEY);
+        let truncated = &signed[..signed.len() - 1];
+        assert_eq!(
+            verify_payload(truncated, Some(KEY)),
+            (PayloadSignature::Invalid, &[][..])
+        );
+    }
+}
@@ -1,7 +1,12 @@
 use crate::cache::DirworldCache;
 use crate::{
-    components::DirworldEntity,
-    resources::{DirworldCodecs, DirworldObservers, EntryType},
+    commands::DirworldCommands,
+    components::{ContentType, DirworldEntity, UntrustedPayload},
+    content::sniff_file_content_type,
+    door::{is_locked_door, locked_door},
+    resources::{
+        DirworldCodecs, DirworldObservers, DirworldPayloadMigrations, DirworldWorldKey, EntryType,
+    },
     utils::extract_entity_payload,
     Extensions,
 };
@@ -43,22 +48,25 @@ pub enum PreloadState {
 
 /// Initiates loading of an asset
 // TODO: Make into a command extension
+#[allow(clippy::too_many_arguments)]
 pub fn load_entity(
     entry: &PathBuf,
     cache: &mut DirworldCache,
     codecs: &DirworldCodecs,
+    migrations: &DirworldPayloadMigrations,
+    world_key: &DirworldWorldKey,
     observers: &DirworldObservers,
     commands: &mut Commands,
     preload_state: &mut NextState<PreloadState>,
     room_assets: &mut RoomAssets,
 ) {
-    let (mut payload, data) = extract_entity_payload(&entry, &codecs);
+    let (mut payload, data, signature) =
+        extract_entity_payload(&entry, &codecs, migrations, world_key);
     payload = payload.map(|p| cache.get_entity_cache(&entry).unwrap_or(p));
-    let entry_type = if entry.is_dir() {
-        EntryType::Folder
-    } else {
-        EntryType::File(entry.extensions())
-    };
+    let locked_door = locked_door(entry, payload.as_ref());
+    let has_source_references = payload
+        .as_ref()
+        .is_some_and(|payload| payload.has_source_references());
     let transform = payload
         .as_ref()
         .map(|payload| payload.transform.clone())
@@ -73,10 +81,70 @@ pub fn load_entity(
             },
         ))
         .id();
-    if let Some(observer) = observers.get(&entry_type) {
+    if let Some(locked_door) = locked_door {
+        commands.entity(entity).insert(locked_door);
+    }
+    if !signature.is_trusted() {
+        warn!("Payload of {entry:?} failed verification: {signature:?}");
+        commands.entity(entity).insert(UntrustedPayload(signature));
+    }
+    if has_source_references {
+        // Queued before the preload trigger, so observers see the referenced sources
+        commands.dirworld_resolve_references(entity);
+    }
+    // Sniffed once here, so spawning does not read the file again
+    let content_type = sniff_entry_content_type(entry, observers);
+    if let Some(content_type) = content_type {
+        commands
+            .entity(entity)
+            .insert(ContentType(content_type.to_string()));
+    }
+    if let Some(observer) = entry_observer(entry, content_type, observers) {
         preload_state.set(PreloadState::Loading);
         room_assets.insert(entry.clone(), HashMap::default());
-        commands.trigger_targets(DirworldPreload { entity, data }, observer.clone());
+        commands.trigger_targets(DirworldPreload { entity, data }, observer);
         info!("Triggered preload for {entry:?}");
     }
 }
+
+/// Finds the observer registered for the type of a filesystem entry. Locked doors fall back to the
+/// observer registered for their file extensions, and files without one for their extensions fall
+/// back to the observer registered for their content type, if it was sniffed.
+pub(crate) fn entry_observer(
+    entry: &PathBuf,
+    content_type: Option<&str>,
+    observers: &DirworldObservers,
+) -> Option<Entity> {
+    let entry_type = if entry.is_dir() {
+        EntryType::Folder
+    } else {
+        EntryType::File(entry.extensions())
+    };
+    if is_locked_door(entry) {
+        if let Some(observer) = observers.get(&EntryType::LockedDoor) {
+            return Some(*observer);
+        }
+    }
+    if let Some(observer) = observers.get(&entry_type) {
+        return Some(*observer);
+    }
+    observers
+        .get(&EntryType::Content(content_type?.to_string()))
+        .copied()
+}
+
+/// Sniffs the content type of a file which can only be handled by an observer registered for an
+/// [`EntryType::Content`]. Nothing is read if no such observer is registered, or the entry is a
+/// folder or has an observer registered for its type already.
+pub(crate) fn sniff_entry_content_type(
+    entry: &PathBuf,
+    observers: &DirworldObservers,
+) -> Option<&'static str> {
+    if !observers.has_content_observers
+        || entry.is_dir()
+        || entry_observer(entry, None, observers).is_some()
+    {
+        return None;
+    }
+    sniff_file_content_type(entry).ok().flatten()
+}
@@ -1,8 +1,12 @@
 use bevy::prelude::*;
 
-use crate::{components::DirworldEntity, events::DirworldSpawn, resources::{DirworldObservers, EntryType}, Extensions};
+use crate::{
+    components::{ContentType, DirworldEntity},
+    events::DirworldSpawn,
+    resources::DirworldObservers,
+};
 
-use super::{PreloadState, RoomAssets};
+use super::{entry_observer, PreloadState, RoomAssets};
 
 pub fn handle_preload(
     asset_server: Res<AssetServer>,
@@ -21,20 +25,16 @@ pub fn handle_preload(
 }
 
 pub fn handle_spawn(
-    dirworld_entity_query: Query<(Entity, &DirworldEntity)>,
+    dirworld_entity_query: Query<(Entity, &DirworldEntity, Option<&ContentType>)>,
     mut commands: Commands,
     observers: Res<DirworldObservers>,
 ) {
     info!("Spawning");
-    for (entity, DirworldEntity { path, .. }) in dirworld_entity_query.iter() {
-        let entry_type = if path.is_dir() {
-            EntryType::Folder
-        } else {
-            EntryType::File(path.extensions())
-        };
-        if let Some(observer) = observers.get(&entry_type) {
-            info!("Found observer {observer:?} for {entry_type:?}");
-            commands.trigger_targets(DirworldSpawn(entity), observer.clone());
+    for (entity, DirworldEntity { path, .. }, content_type) in dirworld_entity_query.iter() {
+        let content_type = content_type.map(|ContentType(content_type)| content_type.as_str());
+        if let Some(observer) = entry_observer(path, content_type, &observers) {
+            info!("Found observer {observer:?} for {path:?}");
+            commands.trigger_targets(DirworldSpawn(entity), observer);
         }
     }
 }
@@ -1,10 +1,30 @@
-use std::{collections::BTreeMap, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use bevy::{ecs::world::CommandQueue, prelude::*, tasks::Task};
+use bevy::{
+    ecs::{system::EntityCommands, world::CommandQueue},
+    prelude::*,
+    tasks::Task,
+};
 use multi_key_map::MultiKeyMap;
-use occule::Codec;
 
-use crate::payload::DirworldEntityPayload;
+use crate::{
+    codecs::{carrier_without_payload, CapacityCodec, PayloadCapacity},
+    content::sniff_content_type,
+    payload::{
+        components::{FromComponents, IntoComponents, PayloadComponent},
+        DirworldEntityPayload, PayloadError, PAYLOAD_SCHEMA_VERSION,
+    },
+    Extensions,
+};
 
 /// Root directory of the world
 #[derive(Resource, Deref, DerefMut, Default)]
@@ -19,17 +39,497 @@ pub struct DirworldCurrentDir{
     pub payload: Option<DirworldEntityPayload>,
 }
 
+/// Secret key payloads are signed with when they are saved and verified against when they are
+/// loaded, so tampering with them can be detected. Insert it before adding
+/// [`crate::DirworldPlugin`]; payloads are neither signed nor verified without one.
+#[derive(Resource, Default, Clone, Deref, DerefMut)]
+pub struct DirworldWorldKey(pub Option<Vec<u8>>);
+
+/// Limits on extracting the archives of locked doors, which guard against archives crafted to
+/// exhaust disk space
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct DirworldExtractionLimits {
+    /// Maximum total size of extracted files in bytes
+    pub max_size: u64,
+    /// Maximum number of archive entries
+    pub max_entries: usize,
+}
+
+impl Default for DirworldExtractionLimits {
+    fn default() -> Self {
+        Self {
+            max_size: 4 * 1024 * 1024 * 1024,
+            max_entries: 100_000,
+        }
+    }
+}
+
+/// Settings for syncing changes to ECS components back into the payloads of dirworld entities,
+/// and for writing changed payloads to disk
+#[derive(Resource, Clone, Copy, Debug, Default)]
+pub struct DirworldSyncSettings {
+    /// Whether to sync [`Transform`]s and components with a sync registered in
+    /// [`DirworldComponentMappings`] into payloads. Disabled by default.
+    pub enabled: bool,
+    /// When changed payloads are written to disk
+    pub persistence: DirworldPersistence,
+}
+
+/// When the changed payloads of dirworld entities, marked with
+/// [`crate::components::Unsaved`], are written to disk
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DirworldPersistence {
+    /// Only when [`crate::commands::DirworldCommands::dirworld_save_changes`] is called
+    #[default]
+    Manual,
+    /// When leaving the room the entities are in
+    OnRoomLeave,
+    /// Periodically, at the given interval
+    Interval(Duration),
+}
+
 /// Running background tasks
 #[derive(Default, Resource, Deref, DerefMut)]
-pub struct DirworldTasks(pub BTreeMap<String, Task<Option<CommandQueue>>>);
+pub struct DirworldTasks(pub BTreeMap<DirworldTaskId, DirworldTask>);
+
+/// Unique identifier of a background task
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DirworldTaskId(pub u64);
+
+impl DirworldTaskId {
+    /// Create a new unique task identifier
+    pub fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl Default for DirworldTaskId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Kind of work performed by a background task
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DirworldTaskKind {
+    /// Locking a door
+    LockDoor,
+    /// Unlocking a door
+    UnlockDoor,
+    /// Changing the key of a locked door
+    RekeyDoor,
+}
+
+/// Handle to a running background task
+pub struct DirworldTask {
+    /// Kind of work performed by this task
+    pub kind: DirworldTaskKind,
+    /// Path of the filesystem entry this task operates on
+    pub path: PathBuf,
+    /// Progress of this task
+    pub progress: DirworldTaskProgress,
+    /// Token used to request cancellation of this task
+    pub cancellation: DirworldCancellationToken,
+    /// The task itself, which returns commands to apply to the world once it completes. Tasks
+    /// which are cancelled return `None`.
+    pub task: Task<Option<CommandQueue>>,
+}
+
+impl DirworldTask {
+    /// Request cancellation of this task. Tasks stop at the next opportunity, which may be after
+    /// they have already completed.
+    pub fn cancel(&self) {
+        self.cancellation.cancel();
+    }
+}
+
+/// Token shared between a background task and the world, used to request cancellation
+#[derive(Default, Clone, Debug)]
+pub struct DirworldCancellationToken(Arc<AtomicBool>);
+
+impl DirworldCancellationToken {
+    /// Request cancellation
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether cancellation has been requested
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Progress of a background task in bytes processed, shared between the task and the world
+#[derive(Default, Clone, Debug)]
+pub struct DirworldTaskProgress {
+    processed: Arc<AtomicU64>,
+    total: Arc<AtomicU64>,
+}
+
+impl DirworldTaskProgress {
+    /// Number of bytes processed so far
+    pub fn processed(&self) -> u64 {
+        self.processed.load(Ordering::Relaxed)
+    }
+
+    /// Estimated total number of bytes to process, or 0 if not yet known
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// Fraction of the task completed, between 0 and 1
+    pub fn fraction(&self) -> f32 {
+        match self.total() {
+            0 => 0.0,
+            total => (self.processed() as f64 / total as f64).min(1.0) as f32,
+        }
+    }
+
+    /// Set the estimated total number of bytes to process
+    pub fn set_total(&self, total: u64) {
+        self.total.store(total, Ordering::Relaxed);
+    }
+
+    /// Record additional processed bytes
+    pub fn add(&self, processed: u64) {
+        self.processed.fetch_add(processed, Ordering::Relaxed);
+    }
+}
 
 /// A map between file types and their corresponding preload/spawn callback observers
-#[derive(Debug, Default, Resource, Deref, DerefMut)]
-pub struct DirworldObservers(pub MultiKeyMap<EntryType, Entity>);
+#[derive(Debug, Default, Resource, Deref)]
+pub struct DirworldObservers {
+    /// Observers by the entry types they are registered for
+    #[deref]
+    pub observers: MultiKeyMap<EntryType, Entity>,
+    /// Whether any observer is registered for an [`EntryType::Content`], without which files are
+    /// never sniffed for their content type
+    pub has_content_observers: bool,
+}
+
+impl DirworldObservers {
+    /// Register an observer for the given entry types
+    pub fn register(&mut self, entry_types: Vec<EntryType>, observer: Entity) {
+        self.has_content_observers |= entry_types
+            .iter()
+            .any(|entry_type| matches!(entry_type, EntryType::Content(_)));
+        self.observers.insert_many(entry_types, observer);
+    }
+}
 
-/// A map between file extensions and their corresponding [`Codec`]s
+/// Codec registered in [`DirworldCodecs`], shared between every extension it is registered for
+pub type RegisteredCodec = Arc<dyn CapacityCodec + Send + Sync>;
+
+/// A map between file extensions and content types and their corresponding [`CapacityCodec`]s, in
+/// the order they are tried. Payloads are extracted with the first codec that decodes one, and
+/// saved with the first codec they fit in. Cloning only clones references to the codecs.
+#[derive(Default, Clone, Resource)]
+pub struct DirworldCodecs {
+    /// Codecs registered for file extensions
+    pub extensions: HashMap<String, Vec<RegisteredCodec>>,
+    /// Codecs registered for content types sniffed from files, e.g. `image/png`, tried after those
+    /// registered for their extensions
+    pub content_types: HashMap<String, Vec<RegisteredCodec>>,
+}
+
+impl DirworldCodecs {
+    /// Register a codec for files with the given extensions, after any codecs already registered
+    /// for them
+    pub fn register(
+        &mut self,
+        extensions: Vec<String>,
+        codec: impl CapacityCodec + Send + Sync + 'static,
+    ) {
+        let codec: RegisteredCodec = Arc::new(codec);
+        for extensions in extensions {
+            self.extensions
+                .entry(extensions)
+                .or_default()
+                .push(codec.clone());
+        }
+    }
+
+    /// Register a codec for files with the given content types, after any codecs already
+    /// registered for them. See [`crate::content::sniff_content_type`] for the content types
+    /// recognized.
+    pub fn register_for_content_types(
+        &mut self,
+        content_types: Vec<String>,
+        codec: impl CapacityCodec + Send + Sync + 'static,
+    ) {
+        let codec: RegisteredCodec = Arc::new(codec);
+        for content_type in content_types {
+            self.content_types
+                .entry(content_type)
+                .or_default()
+                .push(codec.clone());
+        }
+    }
+
+    /// Codecs registered for files with the given extensions, in the order they are tried
+    pub fn codecs(&self, extensions: &str) -> &[RegisteredCodec] {
+        self.extensions
+            .get(extensions)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Codecs for a file with the given extensions and data, in the order they are tried: those
+    /// registered for its extensions, followed by those registered for its content type. Files
+    /// are only sniffed for their content type if any codecs are registered for content types.
+    pub fn file_codecs(&self, extensions: &str, data: &[u8]) -> Vec<RegisteredCodec> {
+        let mut codecs = self.codecs(extensions).to_vec();
+        if let Some(content_codecs) = (!self.content_types.is_empty())
+            .then(|| sniff_content_type(data))
+            .flatten()
+            .and_then(|content_type| self.content_types.get(content_type))
+        {
+            for codec in content_codecs {
+                if !codecs.iter().any(|other| Arc::ptr_eq(other, codec)) {
+                    codecs.push(codec.clone());
+                }
+            }
+        }
+        codecs
+    }
+
+    /// Whether a file with the given extensions may have codecs, i.e. any are registered for its
+    /// extensions or for content types, so it need not be read if not
+    pub fn has_file_codecs(&self, extensions: &str) -> bool {
+        !self.codecs(extensions).is_empty() || !self.content_types.is_empty()
+    }
+
+    /// How many payload bytes a carrier with the given extensions can hold in whichever of its
+    /// codecs holds the most, or `None` if no codec is registered for it
+    pub fn capacity(&self, extensions: &str, carrier: &[u8]) -> Option<PayloadCapacity> {
+        self.file_codecs(extensions, carrier)
+            .iter()
+            .map(|codec| codec.capacity(carrier))
+            .max()
+    }
+
+    /// How many payload bytes the file at the given path can hold, replacing any payload it
+    /// carries already, or `None` if no codec is registered for it. Useful for warning about
+    /// payloads which will not fit before saving them.
+    pub fn file_capacity(&self, path: &Path) -> io::Result<Option<PayloadCapacity>> {
+        let extensions = path.to_path_buf().extensions().unwrap_or_default();
+        if !self.has_file_codecs(&extensions) {
+            return Ok(None);
+        }
+        let raw_carrier = fs::read(path)?;
+        let codecs = self.file_codecs(&extensions, &raw_carrier);
+        let carrier = carrier_without_payload(&codecs, raw_carrier)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{e:?}")))?;
+        Ok(codecs.iter().map(|codec| codec.capacity(&carrier)).max())
+    }
+}
+
+/// Function migrating serialized payload data from one schema version to another
+pub type PayloadMigration =
+    Arc<dyn Fn(&[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> + Send + Sync>;
+
+/// Registered migrations between payload schema versions, indexed by the version they migrate
+/// from
+#[derive(Resource, Clone, Deref, DerefMut)]
+pub struct DirworldPayloadMigrations(pub BTreeMap<u32, (u32, PayloadMigration)>);
+
+impl Default for DirworldPayloadMigrations {
+    fn default() -> Self {
+        let mut migrations = Self(BTreeMap::new());
+        // Unversioned payloads only lack fields which have since been appended with defaults
+        migrations.register(0, 1, |data| Ok(data.to_vec()));
+        // Version 2 appended custom components
+        migrations.register(1, 2, |data| Ok(data.to_vec()));
+        // Version 3 only added compression, which is undone before migrating
+        migrations.register(2, 3, |data| Ok(data.to_vec()));
+        // Version 4 appended source references to actors and scripts
+        migrations.register(3, 4, |data| Ok(data.to_vec()));
+        migrations
+    }
+}
+
+impl DirworldPayloadMigrations {
+    /// Register a migration from one payload schema version to a later one
+    pub fn register(
+        &mut self,
+        from: u32,
+        to: u32,
+        migration: impl Fn(&[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>>
+            + Send
+            + Sync
+            + 'static,
+    ) {
+        self.insert(from, (to, Arc::new(migration)));
+    }
+
+    /// Migrate serialized payload data from the given schema version to the current one
+    pub fn migrate(&self, mut version: u32, mut data: Vec<u8>) -> Result<Vec<u8>, PayloadError> {
+        while version < PAYLOAD_SCHEMA_VERSION {
+            let Some((to, migration)) = self.get(&version).filter(|(to, _)| *to > version) else {
+                return Err(PayloadError::MissingMigration(version));
+            };
+            data = migration(&data).map_err(|e| PayloadError::Migration {
+                from: version,
+                reason: e.to_string(),
+            })?;
+            version = *to;
+        }
+        Ok(data)
+    }
+}
+
+/// Custom payload components registered with
+/// [`crate::DirworldApp::register_payload_component`], indexed by type path
 #[derive(Default, Resource, Deref, DerefMut)]
-pub struct DirworldCodecs(pub MultiKeyMap<String, Box<dyn Codec + Send + Sync>>);
+pub struct DirworldPayloadComponents(pub HashMap<String, PayloadComponentRegistration>);
+
+impl DirworldPayloadComponents {
+    /// Register a custom payload component type
+    pub fn register<T: PayloadComponent>(&mut self) {
+        self.insert(
+            T::type_path().to_string(),
+            PayloadComponentRegistration {
+                deserialize: |data| {
+                    let component: T = rmp_serde::from_slice(data)?;
+                    Ok(Box::new(component))
+                },
+                serialize: |component| {
+                    let component = component.downcast_ref::<T>().ok_or_else(|| {
+                        PayloadError::UnregisteredComponent(
+                            component.reflect_type_path().to_string(),
+                        )
+                    })?;
+                    Ok(rmp_serde::to_vec(component)?)
+                },
+            },
+        );
+    }
+}
+
+/// Function deserializing a custom payload component into its reflected form
+pub type DeserializePayloadComponent = fn(&[u8]) -> Result<Box<dyn Reflect>, PayloadError>;
+
+/// Function serializing a custom payload component from its reflected form
+pub type SerializePayloadComponent = fn(&dyn Reflect) -> Result<Vec<u8>, PayloadError>;
+
+/// Functions converting a registered custom payload component between its serialized form and
+/// its reflected form
+#[derive(Clone, Copy)]
+pub struct PayloadComponentRegistration {
+    /// Deserialize a component
+    pub deserialize: DeserializePayloadComponent,
+    /// Serialize a component
+    pub serialize: SerializePayloadComponent,
+}
+
+/// Function inserting the ECS components corresponding to a payload field into its entity
+pub type InsertPayloadComponent = fn(&DirworldEntityPayload, &mut EntityCommands);
+
+/// Function updating a payload field from the ECS components of its entity, returning whether it
+/// changed
+pub type SyncPayloadComponent = fn(&mut DirworldEntityPayload, &EntityRef) -> bool;
+
+/// Mapping between a payload field and ECS components
+#[derive(Clone, Copy, Default)]
+pub struct PayloadComponentMapping {
+    /// Insert the ECS components when the entity is spawned
+    pub insert: Option<InsertPayloadComponent>,
+    /// Sync changes to the ECS components back into the payload, if enabled in
+    /// [`DirworldSyncSettings`]
+    pub sync: Option<SyncPayloadComponent>,
+}
+
+/// Mappings between payload fields and ECS components, applied when a
+/// [`crate::components::DirworldEntity`] is spawned and synced back while it lives. Built-in fields
+/// are mapped under the keys `"name"`, `"rigidbody"` and `"pickup"`, and custom components under
+/// their type path; remove a key to opt out of its mapping.
+#[derive(Resource, Deref, DerefMut)]
+pub struct DirworldComponentMappings(pub BTreeMap<String, PayloadComponentMapping>);
+
+impl Default for DirworldComponentMappings {
+    fn default() -> Self {
+        let mut mappings = Self(BTreeMap::new());
+        mappings.insert(
+            "name".into(),
+            PayloadComponentMapping {
+                insert: Some(|payload, entity| {
+                    if let Some(name) = &payload.name {
+                        name.insert_into(entity);
+                    }
+                }),
+                sync: Some(|payload, entity| sync_field(&mut payload.name, entity)),
+            },
+        );
+        mappings.insert(
+            "rigidbody".into(),
+            PayloadComponentMapping {
+                insert: Some(|payload, entity| {
+                    if let Some(rigidbody) = &payload.rigidbody {
+                        rigidbody.insert_into(entity);
+                    }
+                }),
+                sync: Some(|payload, entity| sync_field(&mut payload.rigidbody, entity)),
+            },
+        );
+        mappings.insert(
+            "pickup".into(),
+            PayloadComponentMapping {
+                insert: Some(|payload, entity| {
+                    if let Some(pickup) = &payload.pickup {
+                        pickup.insert_into(entity);
+                    }
+                }),
+                sync: Some(|payload, entity| sync_field(&mut payload.pickup, entity)),
+            },
+        );
+        mappings
+    }
+}
+
+impl DirworldComponentMappings {
+    /// Map a custom payload component to ECS components
+    pub fn register<T: PayloadComponent + IntoComponents>(&mut self) {
+        let mapping = self.entry(T::type_path().to_string()).or_default();
+        mapping.insert = Some(|payload, entity| {
+            if let Some(component) = payload.get_component::<T>() {
+                component.insert_into(entity);
+            }
+        });
+    }
+
+    /// Sync changes to the ECS components of a custom payload component back into payloads
+    pub fn register_sync<T: PayloadComponent + FromComponents>(&mut self) {
+        let mapping = self.entry(T::type_path().to_string()).or_default();
+        mapping.sync = Some(|payload, entity| {
+            let Some(component) = T::from_components(entity) else {
+                return payload.remove_component::<T>();
+            };
+            let data = match rmp_serde::to_vec(&component) {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!("Could not serialize {}: {e}", T::type_path());
+                    return false;
+                }
+            };
+            let custom = payload.custom.get_or_insert_with(Default::default);
+            if custom.get(T::type_path()) == Some(&data) {
+                return false;
+            }
+            custom.insert(T::type_path().to_string(), data);
+            true
+        });
+    }
+}
+
+fn sync_field<T: FromComponents + PartialEq>(field: &mut Option<T>, entity: &EntityRef) -> bool {
+    let value = T::from_components(entity);
+    if *field == value {
+        return false;
+    }
+    *field = value;
+    true
+}
 
 /// Type of a filesystem entry
 #[derive(Debug, PartialEq, Eq, Hash)]
@@ -38,5 +538,11 @@ pub enum EntryType {
     File(Option<String>),
     /// A folder
     Folder,
+    /// A locked door. Locked doors without an observer registered for this type fall back to the
+    /// observer for their file extensions.
+    LockedDoor,
+    /// A file whose contents match a content type, e.g. `image/png`. Only used for files without
+    /// an observer registered for their extensions, such as a PNG named `photo.backup`.
+    Content(String),
 }
 
@@ -1,30 +1,148 @@
+use std::time::Duration;
+
 use bevy::{
     prelude::*,
     tasks::{block_on, futures_lite::future},
 };
+use uuid::Uuid;
 
-use crate::resources::DirworldTasks;
+use crate::{
+    commands::DirworldCommands,
+    components::{DirworldEntity, Persist, Unsaved},
+    events::{DirworldTaskCancelled, DirworldTaskFinished},
+    payload::DirworldEntityPayload,
+    resources::{
+        DirworldComponentMappings, DirworldPersistence, DirworldSyncSettings, DirworldTasks,
+        SyncPayloadComponent,
+    },
+};
 
-pub fn remove_completed_tasks(mut commands: Commands, mut tasks: ResMut<DirworldTasks>) {
-    tasks.retain(|_, task| {
-        if task.is_finished() {
-            if let Some(Some(mut command_queue)) = block_on(future::poll_once(&mut *task)) {
+pub fn remove_completed_tasks(
+    mut commands: Commands,
+    mut tasks: ResMut<DirworldTasks>,
+    mut finished_event_writer: EventWriter<DirworldTaskFinished>,
+    mut cancelled_event_writer: EventWriter<DirworldTaskCancelled>,
+) {
+    tasks.retain(|id, task| {
+        if !task.task.is_finished() {
+            return true;
+        }
+        match block_on(future::poll_once(&mut task.task)).flatten() {
+            Some(mut command_queue) => {
                 commands.append(&mut command_queue);
             }
+            None if task.cancellation.is_cancelled() => {
+                cancelled_event_writer.send(DirworldTaskCancelled {
+                    id: *id,
+                    kind: task.kind,
+                    path: task.path.clone(),
+                });
+                return false;
+            }
+            None => {}
         }
-        !task.is_finished()
+        finished_event_writer.send(DirworldTaskFinished {
+            id: *id,
+            kind: task.kind,
+            path: task.path.clone(),
+        });
+        false
     });
 }
 
-// pub fn sync_entity_transforms(
-//     mut dirworld_entity_query: Query<(&mut DirworldEntity, Ref<Transform>, &GlobalTransform)>,
-// ) {
-//     for (mut dirworld_entity, transform, global_transform) in dirworld_entity_query.iter_mut() {
-//         if transform.is_changed() && !transform.is_added() {
-//             if let Some(payload) = &mut dirworld_entity.payload {
-//                 let transform = global_transform.compute_transform();
-//                 *payload.transform = transform;
-//             }
-//         }
-//     }
-// }
+/// Sync transforms of dirworld entities moved at runtime into their payloads. Entities with
+/// [`Persist`] are not synced, since they are carried between rooms.
+pub fn sync_entity_transforms(
+    mut dirworld_entity_query: Query<(&mut DirworldEntity, Ref<Transform>), Without<Persist>>,
+) {
+    for (mut dirworld_entity, transform) in dirworld_entity_query.iter_mut() {
+        if !transform.is_changed() || transform.is_added() {
+            continue;
+        }
+        // Only touch the payload if it differs, so unchanged entities are not marked as unsaved
+        if dirworld_entity
+            .payload
+            .as_ref()
+            .is_some_and(|payload| *payload.transform == *transform)
+        {
+            continue;
+        }
+        *dirworld_entity
+            .payload
+            .get_or_insert_with(DirworldEntityPayload::new)
+            .transform = *transform;
+    }
+}
+
+/// Sync ECS components with a sync registered in [`DirworldComponentMappings`] into the payloads
+/// of their dirworld entities
+pub fn sync_mapped_components(world: &mut World) {
+    let syncs: Vec<SyncPayloadComponent> = world
+        .resource::<DirworldComponentMappings>()
+        .values()
+        .filter_map(|mapping| mapping.sync)
+        .collect();
+    let entities: Vec<Entity> = world
+        .query_filtered::<Entity, (With<DirworldEntity>, Without<Persist>)>()
+        .iter(world)
+        .collect();
+    for entity in entities {
+        // Take the payload out without triggering change detection, so it can be updated while
+        // reading the entity's other components
+        let Some(payload) = world
+            .get_mut::<DirworldEntity>(entity)
+            .map(|mut dirworld_entity| dirworld_entity.bypass_change_detection().payload.take())
+        else {
+            continue;
+        };
+        let had_payload = payload.is_some();
+        let mut payload = payload.unwrap_or_default();
+        let entity_ref = world.entity(entity);
+        let mut changed = false;
+        for sync in &syncs {
+            changed |= sync(&mut payload, &entity_ref);
+        }
+
+        let Some(mut dirworld_entity) = world.get_mut::<DirworldEntity>(entity) else {
+            continue;
+        };
+        if changed {
+            if !had_payload {
+                payload.id = Uuid::new_v4();
+            }
+            dirworld_entity.payload = Some(payload);
+        } else {
+            dirworld_entity.bypass_change_detection().payload = had_payload.then_some(payload);
+        }
+    }
+}
+
+/// Mark dirworld entities whose payloads changed after they were spawned as [`Unsaved`]
+pub fn mark_unsaved(
+    mut commands: Commands,
+    dirworld_entity_query: Query<(Entity, Ref<DirworldEntity>), Without<Unsaved>>,
+) {
+    for (entity, dirworld_entity) in dirworld_entity_query.iter() {
+        if dirworld_entity.is_changed() && !dirworld_entity.is_added() {
+            commands.entity(entity).insert(Unsaved);
+        }
+    }
+}
+
+/// Save changed payloads periodically, if configured in [`DirworldSyncSettings`]
+pub fn persist_changes_on_interval(
+    mut commands: Commands,
+    settings: Res<DirworldSyncSettings>,
+    time: Res<Time>,
+    mut elapsed: Local<Duration>,
+) {
+    let DirworldPersistence::Interval(interval) = settings.persistence else {
+        *elapsed = Duration::ZERO;
+        return;
+    };
+    *elapsed += time.delta();
+    if *elapsed >= interval {
+        *elapsed = Duration::ZERO;
+        commands.dirworld_save_changes();
+    }
+}
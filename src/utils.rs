@@ -1,24 +1,65 @@
-use std::{fs, path::PathBuf};
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
 
 use bevy::prelude::*;
 
 use crate::{
-    components::DirworldEntity, payload::DirworldEntityPayload, resources::DirworldCodecs, Extensions
+    codecs::TrailerCodec, components::DirworldEntity, door::{is_locked_door, is_streamable_door}, payload::{DirworldEntityPayload, PayloadSignature}, resources::{DirworldCodecs, DirworldPayloadMigrations, DirworldWorldKey}, Extensions
 };
 
-/// Extracts the binary payload from a file
+/// Extension of sidecar files, which hold the payloads of files that cannot carry them
+pub const SIDECAR_EXTENSION: &str = "dirworld";
+
+/// Name of the human-readable RON alternative to the `.door` file holding a directory's payload,
+/// read if the directory has no `.door` file
+pub const READABLE_DOOR_FILE: &str = ".door.ron";
+
+/// Hidden sidecar file next to a file, e.g. `.name.ext.dirworld`, holding its payload when it has
+/// no matching [`occule::Codec`] to embed it with
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let file_name = path
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{file_name}.{SIDECAR_EXTENSION}"))
+}
+
+/// Remove the sidecar file of a file whose payload has moved elsewhere, if it has one
+pub fn remove_sidecar(path: &Path) {
+    let sidecar = sidecar_path(path);
+    if sidecar.exists() {
+        if let Err(e) = fs::remove_file(&sidecar) {
+            warn!("Failed to remove sidecar file {sidecar:?}: {e}");
+        }
+    }
+}
+
+/// Extracts the binary payload from a file, migrating it to the current schema version and
+/// verifying its signature against the world key. Payloads are read from the `.door` or
+/// [`READABLE_DOOR_FILE`] file in directories, and from the file itself or its sidecar file
+/// otherwise. Locked doors whose archive is streamed when unlocked are never read in full, so no
+/// carrier is returned for them.
 pub fn extract_entity_payload(
     path: &PathBuf,
     codecs: &DirworldCodecs,
-) -> (Option<DirworldEntityPayload>, Option<Vec<u8>>) {
+    migrations: &DirworldPayloadMigrations,
+    world_key: &DirworldWorldKey,
+) -> (Option<DirworldEntityPayload>, Option<Vec<u8>>, PayloadSignature) {
+    let key = world_key.as_deref();
     let mut data = None;
     let mut payload = None;
+    let mut signature = PayloadSignature::Unchecked;
 
     if path.is_dir() {
         let payload_file_path = path.join(".door");
         if payload_file_path.exists() {
             if let Ok(payload_file_data) = fs::read(&payload_file_path) {
-                match rmp_serde::from_slice::<DirworldEntityPayload>(&payload_file_data) {
+                let (payload_signature, result) =
+                    DirworldEntityPayload::from_signed_bytes(&payload_file_data, key, migrations);
+                signature = payload_signature;
+                match result {
                     Ok(deserialized_payload) => {
                         payload = Some(deserialized_payload);
                     }
@@ -27,40 +68,96 @@ pub fn extract_entity_payload(
                     }
                 }
             }
+        } else if let Ok(text) = fs::read_to_string(path.join(READABLE_DOOR_FILE)) {
+            // Readable payloads are meant to be edited by hand, so they are never signed
+            if key.is_some() {
+                signature = PayloadSignature::Unsigned;
+            }
+            match DirworldEntityPayload::from_ron(&text) {
+                Ok(deserialized_payload) => {
+                    payload = Some(deserialized_payload);
+                }
+                Err(e) => {
+                    warn!("Could not import readable payload: {e}");
+                }
+            }
         }
+    } else if is_locked_door(path) && is_streamable_door(path).unwrap_or(false) {
+        (payload, signature) = extract_streamable_door_payload(path, migrations, world_key);
     } else {
-        if let Some(extensions) = path.extensions() {
-            if let Ok(file_data) = fs::read(&path) {
-                if let Some(codec) = codecs.get(&extensions) {
-                    match codec.decode(&file_data) {
-                        Ok((carrier, extracted_payload)) => {
-                            match rmp_serde::from_slice::<DirworldEntityPayload>(&extracted_payload)
-                            {
-                                Ok(deserialized_payload) => {
-                                    data = Some(carrier);
-                                    payload = Some(deserialized_payload);
-                                }
-                                Err(e) => {
-                                    warn!("Could not deserialize extracted payload: {e:?}");
-                                    data = Some(file_data);
-                                }
+        let extensions = path.extensions().unwrap_or_default();
+        if let Ok(file_data) = fs::read(&path) {
+            // Codecs are tried in order until one yields a payload
+            for codec in codecs.file_codecs(&extensions, &file_data) {
+                match codec.decode(&file_data) {
+                    Ok((carrier, extracted_payload)) => {
+                        let (payload_signature, result) = DirworldEntityPayload::from_signed_bytes(
+                            &extracted_payload,
+                            key,
+                            migrations,
+                        );
+                        // Kept even if deserialization fails, so an unreadable payload is not
+                        // taken for a missing one
+                        signature = payload_signature;
+                        match result {
+                            Ok(deserialized_payload) => {
+                                data = Some(carrier);
+                                payload = Some(deserialized_payload);
+                                break;
                             }
-                        }
-                        Err(e) => match e {
-                            occule::Error::DataNotEncoded => {
-                                data = Some(file_data);
+                            Err(e) => {
+                                warn!("Could not deserialize extracted payload: {e:?}");
                             }
-                            _ => error!("Could not decode payload: {e:?}"),
-                        },
+                        }
                     }
-                } else {
-                    data = Some(file_data);
+                    Err(e) => match e {
+                        occule::Error::DataNotEncoded => {}
+                        _ => error!("Could not decode payload: {e:?}"),
+                    },
                 }
             }
+            if data.is_none() {
+                data = Some(file_data);
+            }
+        }
+        if payload.is_none() {
+            if let Ok(sidecar_data) = fs::read(sidecar_path(path)) {
+                let (payload_signature, result) =
+                    DirworldEntityPayload::from_signed_bytes(&sidecar_data, key, migrations);
+                signature = payload_signature;
+                payload = result
+                    .inspect_err(|e| warn!("Could not deserialize sidecar payload: {e:?}"))
+                    .ok();
+            }
         }
     }
 
-    (payload, data)
+    (payload, data, signature)
+}
+
+/// Extracts the payload of a locked door whose version 2 archive is streamed from the file when
+/// unlocked, without reading the archive. Its payload is kept in its sidecar file, so saving it
+/// never rewrites the archive, or otherwise read on its own from the end of the file if it was
+/// appended with a [`TrailerCodec`].
+fn extract_streamable_door_payload(
+    path: &Path,
+    migrations: &DirworldPayloadMigrations,
+    world_key: &DirworldWorldKey,
+) -> (Option<DirworldEntityPayload>, PayloadSignature) {
+    let payload_data = fs::read(sidecar_path(path)).ok().or_else(|| {
+        File::open(path)
+            .ok()
+            .and_then(|file| TrailerCodec.decode_payload(file).ok())
+    });
+    let Some(payload_data) = payload_data else {
+        return (None, PayloadSignature::Unchecked);
+    };
+    let (signature, result) =
+        DirworldEntityPayload::from_signed_bytes(&payload_data, world_key.as_deref(), migrations);
+    let payload = result
+        .inspect_err(|e| warn!("Could not deserialize locked door payload: {e:?}"))
+        .ok();
+    (payload, signature)
 }
 
 /// Despawns an entity corresponding to a path on the filesystem